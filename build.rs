@@ -0,0 +1,25 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Capture the git commit that we build from, for the `hydrant_build_info` metric.
+
+use std::process::Command;
+
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=HYDRANT_GIT_SHA={}", git_sha);
+
+    // Rebuild when the checked out commit changes. If we are not in a git
+    // repository, these paths do not exist, and Cargo reruns the script
+    // every build, which is harmless.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Reading the accounts to monitor from a file.
+//!
+//! The file contains one base58 pubkey per line. Blank lines are ignored, and
+//! so is everything after a `#`, so a line can carry a comment about the
+//! account, e.g. `GoatPubkey... # treasury`.
+//!
+//! On SIGHUP, the daemon reads the file again, and starts or stops monitoring
+//! the accounts that were added or removed.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use nix::sys::signal::{SigSet, Signal};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::{Error, InvalidAccountsFileLineError};
+
+/// Parse the contents of an accounts file.
+///
+/// Fails on the first line that is not a valid pubkey.
+pub fn parse_accounts_file(
+    contents: &str,
+) -> std::result::Result<Vec<Pubkey>, InvalidAccountsFileLineError> {
+    let mut accounts = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let text = line.split('#').next().unwrap_or("").trim();
+        if text.is_empty() {
+            continue;
+        }
+        match Pubkey::from_str(text) {
+            Ok(pubkey) => accounts.push(pubkey),
+            Err(..) => {
+                return Err(InvalidAccountsFileLineError {
+                    line_number: i + 1,
+                    text: text.to_string(),
+                })
+            }
+        }
+    }
+    Ok(accounts)
+}
+
+/// Read and parse the accounts file at `path`.
+pub fn read_accounts_file(path: &Path) -> std::result::Result<Vec<Pubkey>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(parse_accounts_file(&contents)?)
+}
+
+/// Accounts that were added to and removed from the accounts file.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct AccountsDiff {
+    /// Accounts in the new file that were not in the old one, in file order.
+    pub added: Vec<Pubkey>,
+
+    /// Accounts in the old file that are not in the new one, in file order.
+    pub removed: Vec<Pubkey>,
+}
+
+/// Compare the accounts of a reloaded file to the current ones.
+pub fn diff_accounts(current: &[Pubkey], new: &[Pubkey]) -> AccountsDiff {
+    let current_set: HashSet<&Pubkey> = current.iter().collect();
+    let new_set: HashSet<&Pubkey> = new.iter().collect();
+    let mut seen = HashSet::new();
+    AccountsDiff {
+        // Deduplicate, an account may be listed more than once.
+        added: new
+            .iter()
+            .filter(|a| !current_set.contains(a) && seen.insert(*a))
+            .copied()
+            .collect(),
+        removed: current
+            .iter()
+            .filter(|a| !new_set.contains(a) && seen.insert(*a))
+            .copied()
+            .collect(),
+    }
+}
+
+/// Set the returned flag whenever we receive SIGHUP.
+///
+/// The daemon checks the flag at the start of every poll, so the reload never
+/// happens in the middle of one. Like [`crate::server::block_shutdown_signals`],
+/// this must be called before any other threads are spawned, because they
+/// inherit the signal mask. Call it before that function, so the shutdown
+/// handler thread does not receive SIGHUP either.
+pub fn request_reload_on_sighup() -> Arc<AtomicBool> {
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGHUP);
+    signals.thread_block().expect("Failed to block SIGHUP.");
+
+    let flag = reload_requested.clone();
+    std::thread::Builder::new()
+        .name("reload_handler".to_string())
+        .spawn(move || {
+            // This thread should only ever receive SIGHUP, leave the shutdown
+            // signals to the shutdown handler, if there is one.
+            let _ = SigSet::all().thread_block();
+            loop {
+                let signal = signals.wait().expect("Failed to wait for SIGHUP.");
+                log::info!("Received {}, reloading the accounts file.", signal);
+                flag.store(true, Ordering::SeqCst);
+            }
+        })
+        .expect("Failed to spawn reload handler thread.");
+
+    reload_requested
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_accounts_file_reads_one_pubkey_per_line() {
+        let contents = "\
+            So11111111111111111111111111111111111111112\n\
+            Stake11111111111111111111111111111111111111\n";
+        assert_eq!(
+            parse_accounts_file(contents).unwrap(),
+            vec![
+                Pubkey::from_str("So11111111111111111111111111111111111111112").unwrap(),
+                Pubkey::from_str("Stake11111111111111111111111111111111111111").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_accounts_file_skips_blank_lines_and_comments() {
+        let contents = "# Wrapped SOL\n\
+            \n\
+            So11111111111111111111111111111111111111112  # the mint\n   \n\
+            # Stake11111111111111111111111111111111111111\n";
+        assert_eq!(
+            parse_accounts_file(contents).unwrap(),
+            vec![Pubkey::from_str("So11111111111111111111111111111111111111112").unwrap()]
+        );
+        assert_eq!(parse_accounts_file("").unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn parse_accounts_file_reports_line_of_invalid_pubkey() {
+        let contents = "So11111111111111111111111111111111111111112\n\
+            # A comment.\n\
+            NotAPubkey0 # typo\n";
+        assert_eq!(
+            parse_accounts_file(contents).err(),
+            Some(InvalidAccountsFileLineError {
+                line_number: 3,
+                text: "NotAPubkey0".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn diff_accounts_reports_added_and_removed() {
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+        let c = Pubkey::new_unique();
+        let d = Pubkey::new_unique();
+
+        assert_eq!(
+            diff_accounts(&[a, b, c], &[c, d, a, d]),
+            AccountsDiff {
+                added: vec![d],
+                removed: vec![b],
+            }
+        );
+        assert_eq!(diff_accounts(&[a, b], &[b, a]), AccountsDiff::default());
+        assert_eq!(
+            diff_accounts(&[], &[a, b]),
+            AccountsDiff {
+                added: vec![a, b],
+                removed: vec![],
+            }
+        );
+    }
+}
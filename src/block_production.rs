@@ -0,0 +1,443 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Block production statistics for a single leader identity.
+
+use std::time::{Duration, SystemTime};
+
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::{
+    RpcBlockProductionConfig, RpcBlockProductionConfigRange, RpcLeaderScheduleConfig,
+};
+use solana_program::clock::{Epoch, Slot};
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily};
+use crate::SnapshotClient;
+
+/// Block production of one leader identity, for the epoch so far, or for a window of recent slots.
+#[derive(Clone)]
+pub struct BlockProduction {
+    /// The leader identity these statistics are about.
+    pub identity: Pubkey,
+
+    /// The epoch these statistics are about.
+    pub epoch: Epoch,
+
+    /// Number of slots in which the identity was leader.
+    pub leader_slots: u64,
+
+    /// Number of blocks that the identity produced in its leader slots.
+    pub blocks_produced: u64,
+}
+
+impl BlockProduction {
+    pub fn skip_rate(&self) -> Option<f64> {
+        get_skip_rate(self.leader_slots, self.blocks_produced)
+    }
+}
+
+/// Read block production of the given leader identity for the current epoch.
+///
+/// The caller passes in the current epoch, because the RPC response only
+/// includes the slot range that it covers.
+pub fn get_block_production(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    identity: Pubkey,
+    epoch: Epoch,
+) -> std::result::Result<BlockProduction, Error> {
+    get_block_production_in_range(rpc_client, commitment, identity, epoch, None)
+}
+
+/// Read block production of the given leader identity for the last `slots` slots.
+///
+/// The window does not extend before the start of the current epoch, so
+/// shortly after an epoch boundary it covers fewer slots.
+pub fn get_rolling_block_production(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    identity: Pubkey,
+    slots: u64,
+) -> std::result::Result<BlockProduction, Error> {
+    let epoch_info = rpc_client.get_epoch_info_with_commitment(commitment)?;
+    let range = get_rolling_range(
+        epoch_info.absolute_slot,
+        epoch_info.absolute_slot - epoch_info.slot_index,
+        slots,
+    );
+    get_block_production_in_range(
+        rpc_client,
+        commitment,
+        identity,
+        epoch_info.epoch,
+        Some(range),
+    )
+}
+
+/// Return the range of the last `slots` slots up to `current_slot`, clamped to the current epoch.
+///
+/// The RPC can only report block production within a single epoch, because
+/// it needs the leader schedule to know the leader slots.
+fn get_rolling_range(
+    current_slot: Slot,
+    epoch_first_slot: Slot,
+    slots: u64,
+) -> RpcBlockProductionConfigRange {
+    RpcBlockProductionConfigRange {
+        first_slot: current_slot.saturating_sub(slots).max(epoch_first_slot),
+        last_slot: Some(current_slot),
+    }
+}
+
+fn get_block_production_in_range(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    identity: Pubkey,
+    epoch: Epoch,
+    range: Option<RpcBlockProductionConfigRange>,
+) -> std::result::Result<BlockProduction, Error> {
+    let config = RpcBlockProductionConfig {
+        identity: Some(identity.to_string()),
+        range,
+        commitment: Some(commitment),
+    };
+    let production = rpc_client.get_block_production_with_config(config)?.value;
+
+    // The identity is absent from the response if it had no leader slots in
+    // the range.
+    let (leader_slots, blocks_produced) = production
+        .by_identity
+        .get(&identity.to_string())
+        .copied()
+        .unwrap_or((0, 0));
+
+    Ok(BlockProduction {
+        identity,
+        epoch,
+        leader_slots: leader_slots as u64,
+        blocks_produced: blocks_produced as u64,
+    })
+}
+
+/// Leader slots of one identity in the current epoch so far, according to the leader schedule.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LeaderSlots {
+    pub identity: Pubkey,
+
+    /// Number of slots assigned to the identity, up to the last slot that block production covers.
+    pub assigned: u64,
+
+    /// Number of those slots in which the identity did not produce a block.
+    pub missed: u64,
+}
+
+/// Read the leader slots of the identity in the current epoch so far, and how many it missed.
+///
+/// We take the assigned slots from the leader schedule, and the blocks from
+/// block production, over the same range of slots.
+pub fn get_leader_slots(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    identity: Pubkey,
+) -> std::result::Result<LeaderSlots, Error> {
+    let epoch_info = rpc_client.get_epoch_info_with_commitment(commitment)?;
+    let epoch_first_slot = epoch_info.absolute_slot - epoch_info.slot_index;
+
+    let schedule_config = RpcLeaderScheduleConfig {
+        identity: Some(identity.to_string()),
+        commitment: Some(commitment),
+    };
+    // The schedule is `None` if the node does not know it, and it lacks the
+    // identity if it has no leader slots in the epoch.
+    let slot_indices = rpc_client
+        .get_leader_schedule_with_config(Some(epoch_info.absolute_slot), schedule_config)?
+        .and_then(|mut schedule| schedule.remove(&identity.to_string()))
+        .unwrap_or_default();
+
+    let production_config = RpcBlockProductionConfig {
+        identity: Some(identity.to_string()),
+        range: Some(RpcBlockProductionConfigRange {
+            first_slot: epoch_first_slot,
+            last_slot: Some(epoch_info.absolute_slot),
+        }),
+        commitment: Some(commitment),
+    };
+    let production = rpc_client
+        .get_block_production_with_config(production_config)?
+        .value;
+    let blocks_produced = production
+        .by_identity
+        .get(&identity.to_string())
+        .map_or(0, |(_leader_slots, blocks_produced)| {
+            *blocks_produced as u64
+        });
+
+    let assigned =
+        count_assigned_slots(&slot_indices, epoch_first_slot, production.range.last_slot);
+    Ok(LeaderSlots {
+        identity,
+        assigned,
+        missed: assigned.saturating_sub(blocks_produced),
+    })
+}
+
+/// Count the leader slots up to and including `last_slot`.
+///
+/// The leader schedule lists slots as indices into the epoch. Leaders get
+/// four consecutive slots, and when `last_slot` lies within such a group, the
+/// slots after it are still to come, they are not assigned so far, and in
+/// particular not missed.
+fn count_assigned_slots(slot_indices: &[usize], epoch_first_slot: Slot, last_slot: Slot) -> u64 {
+    slot_indices
+        .iter()
+        .filter(|index| epoch_first_slot + **index as u64 <= last_slot)
+        .count() as u64
+}
+
+/// Return the fraction of leader slots for which no block was produced.
+///
+/// Returns `None` if there were no leader slots, in which case the skip rate
+/// is undefined. This is the case for every validator at the start of an epoch.
+pub fn get_skip_rate(leader_slots: u64, blocks_produced: u64) -> Option<f64> {
+    if leader_slots == 0 {
+        return None;
+    }
+
+    // The RPC should never report more blocks than leader slots, but if it
+    // does, report a skip rate of zero rather than a negative one.
+    let slots_skipped = leader_slots.saturating_sub(blocks_produced);
+    Some(slots_skipped as f64 / leader_slots as f64)
+}
+
+/// Reports the block production of `--leader-identity`.
+pub struct BlockProductionSource {
+    identity: Pubkey,
+
+    /// Number of recent slots for the rolling skip rate, from `--block-production-slots`.
+    rolling_slots: Option<u64>,
+
+    /// Whether to report the leader slots so far, with `--leader-slots`.
+    leader_slots: bool,
+
+    interval: Duration,
+
+    /// The epoch of the last successful poll.
+    epoch: Option<Epoch>,
+}
+
+impl BlockProductionSource {
+    pub fn new(
+        identity: Pubkey,
+        rolling_slots: Option<u64>,
+        leader_slots: bool,
+        interval: Duration,
+    ) -> BlockProductionSource {
+        BlockProductionSource {
+            identity,
+            rolling_slots,
+            leader_slots,
+            interval,
+            epoch: None,
+        }
+    }
+}
+
+impl MetricSource for BlockProductionSource {
+    fn name(&self) -> &'static str {
+        "block production"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &[
+            "solana_blocks_produced_total",
+            "solana_leader_slots_assigned",
+            "solana_leader_slots_missed",
+            "solana_leader_slots_total",
+            "solana_rolling_skip_rate",
+            "solana_skip_rate",
+        ]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Block production accrues over the epoch, after an epoch rollover the
+    /// statistics of the previous epoch no longer apply, even if it is not yet
+    /// time to poll them again.
+    fn is_current(&self, core: &CoreState) -> bool {
+        self.epoch == Some(core.clock.epoch)
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let rpc_client = client.rpc_client();
+        let commitment = client.commitment();
+        let block_production =
+            get_block_production(rpc_client, commitment, self.identity, core.clock.epoch)?;
+        let rolling_block_production = match self.rolling_slots {
+            Some(slots) => Some(get_rolling_block_production(
+                rpc_client,
+                commitment,
+                self.identity,
+                slots,
+            )?),
+            None => None,
+        };
+        let leader_slots = match self.leader_slots {
+            true => Some(get_leader_slots(rpc_client, commitment, self.identity)?),
+            false => None,
+        };
+        self.epoch = Some(block_production.epoch);
+        Ok(get_block_production_families(
+            &block_production,
+            rolling_block_production.as_ref(),
+            leader_slots.as_ref(),
+            core.produced_at,
+        ))
+    }
+}
+
+/// Return the metric families for the block production of one identity.
+fn get_block_production_families(
+    block_production: &BlockProduction,
+    rolling_block_production: Option<&BlockProduction>,
+    leader_slots: Option<&LeaderSlots>,
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    let identity = block_production.identity.to_string();
+    let mut families = vec![
+        MetricFamily {
+            name: "solana_leader_slots_total",
+            help: "Number of leader slots of the identity in the current epoch",
+            type_: "counter",
+            metrics: vec![Metric::new(block_production.leader_slots)
+                .with_label("identity", identity.clone())
+                .at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_blocks_produced_total",
+            help: "Number of blocks produced by the identity in the current epoch",
+            type_: "counter",
+            metrics: vec![Metric::new(block_production.blocks_produced)
+                .with_label("identity", identity.clone())
+                .at(produced_at)],
+        },
+    ];
+
+    // At the start of an epoch there are no leader slots yet, and the
+    // skip rate is undefined, so we omit it.
+    if let Some(skip_rate) = block_production.skip_rate() {
+        families.push(MetricFamily {
+            name: "solana_skip_rate",
+            help: "Fraction of leader slots of the identity in the current epoch without a block",
+            type_: "gauge",
+            metrics: vec![Metric::new(skip_rate)
+                .with_label("identity", identity.clone())
+                .at(produced_at)],
+        });
+    }
+
+    // Like the epoch skip rate, this is undefined without leader slots in the window.
+    let rolling_skip_rate = rolling_block_production.and_then(BlockProduction::skip_rate);
+    if let Some(skip_rate) = rolling_skip_rate {
+        families.push(MetricFamily {
+            name: "solana_rolling_skip_rate",
+            help: "Fraction of recent leader slots of the identity without a block",
+            type_: "gauge",
+            metrics: vec![Metric::new(skip_rate)
+                .with_label("identity", identity.clone())
+                .at(produced_at)],
+        });
+    }
+
+    if let Some(leader_slots) = leader_slots {
+        families.push(MetricFamily {
+            name: "solana_leader_slots_assigned",
+            help: "Number of leader slots assigned to the identity in the current epoch so far",
+            type_: "gauge",
+            metrics: vec![Metric::new(leader_slots.assigned)
+                .with_label("identity", identity.clone())
+                .at(produced_at)],
+        });
+        families.push(MetricFamily {
+            name: "solana_leader_slots_missed",
+            help: "Number of assigned leader slots so far in which the identity produced no block",
+            type_: "gauge",
+            metrics: vec![Metric::new(leader_slots.missed)
+                .with_label("identity", identity)
+                .at(produced_at)],
+        });
+    }
+
+    families
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_skip_rate_without_leader_slots_is_undefined() {
+        assert_eq!(get_skip_rate(0, 0), None);
+    }
+
+    #[test]
+    fn get_skip_rate_computes_fraction_skipped() {
+        assert_eq!(get_skip_rate(4, 4), Some(0.0));
+        assert_eq!(get_skip_rate(4, 3), Some(0.25));
+        assert_eq!(get_skip_rate(4, 0), Some(1.0));
+    }
+
+    #[test]
+    fn get_skip_rate_does_not_go_negative() {
+        assert_eq!(get_skip_rate(4, 5), Some(0.0));
+    }
+
+    #[test]
+    fn get_rolling_range_ends_at_current_slot() {
+        assert_eq!(
+            get_rolling_range(1_000_500, 1_000_000, 100),
+            RpcBlockProductionConfigRange {
+                first_slot: 1_000_400,
+                last_slot: Some(1_000_500),
+            }
+        );
+    }
+
+    #[test]
+    fn get_rolling_range_does_not_extend_before_epoch_start() {
+        assert_eq!(
+            get_rolling_range(1_000_050, 1_000_000, 100),
+            RpcBlockProductionConfigRange {
+                first_slot: 1_000_000,
+                last_slot: Some(1_000_050),
+            }
+        );
+        // Also near genesis, where the subtraction would underflow.
+        assert_eq!(get_rolling_range(50, 0, 100).first_slot, 0);
+    }
+
+    #[test]
+    fn count_assigned_slots_stops_at_last_slot() {
+        let slot_indices = [0, 1, 2, 3, 8, 9, 10, 11];
+        assert_eq!(count_assigned_slots(&slot_indices, 1_000, 999), 0);
+        assert_eq!(count_assigned_slots(&slot_indices, 1_000, 1_005), 4);
+        // Mid-way through the second group, only the slots so far count.
+        let assigned = count_assigned_slots(&slot_indices, 1_000, 1_009);
+        assert_eq!(assigned, 6);
+        assert_eq!(count_assigned_slots(&slot_indices, 1_000, 1_500), 8);
+
+        // With 5 blocks in those 6 slots, one is missed, and we never report
+        // a negative number of missed slots.
+        assert_eq!(assigned.saturating_sub(5), 1);
+        assert_eq!(assigned.saturating_sub(7), 0);
+    }
+}
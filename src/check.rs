@@ -0,0 +1,135 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Startup check that all configured accounts exist, for `--check`.
+//!
+//! A typo in a watched address otherwise only shows up as a polling error
+//! once the daemon is running.
+
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::Error;
+use crate::snapshot::{OutputMode, SnapshotClientConfig};
+use crate::Opts;
+
+/// Whether a configured account exists.
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct AccountCheck {
+    /// The option that the account was configured with.
+    pub option: &'static str,
+
+    #[serde(serialize_with = "crate::serialize_pubkey")]
+    pub address: Pubkey,
+
+    pub exists: bool,
+}
+
+/// Return all accounts in the configuration, with the option they were configured with.
+pub fn get_configured_accounts(opts: &Opts) -> Vec<(&'static str, Pubkey)> {
+    let mut accounts = Vec::new();
+    accounts.extend(opts.watch_mint.iter().map(|a| ("--watch-mint", *a)));
+    accounts.extend(
+        opts.watch_token_account
+            .iter()
+            .map(|a| ("--watch-token-account", *a)),
+    );
+    accounts.extend(opts.fee_account.iter().map(|a| ("--fee-account", *a)));
+    accounts.extend(opts.watch_stake.iter().map(|a| ("--watch-stake", *a)));
+    accounts.extend(opts.reward_account.iter().map(|a| ("--reward-account", *a)));
+    accounts.extend(
+        opts.custom_field
+            .iter()
+            .map(|field| ("--custom-field", field.account)),
+    );
+    accounts.extend(
+        opts.count_token_holders
+            .iter()
+            .map(|a| ("--count-token-holders", *a)),
+    );
+    accounts
+}
+
+/// Read all given accounts in one snapshot, and report which ones exist.
+pub fn check_accounts(
+    config: &mut SnapshotClientConfig,
+    accounts: &[(&'static str, Pubkey)],
+) -> std::result::Result<Vec<AccountCheck>, Error> {
+    let result = config.with_snapshot(|config| {
+        let mut checks = Vec::with_capacity(accounts.len());
+        for &(option, address) in accounts {
+            checks.push(AccountCheck {
+                option,
+                address,
+                exists: config.client.get_account_if_exists(&address)?.is_some(),
+            });
+        }
+        Ok(checks)
+    })?;
+    Ok(result.value)
+}
+
+/// Print the outcome of the check to stdout.
+pub fn print_account_checks(checks: &[AccountCheck], output_mode: OutputMode) {
+    match output_mode {
+        OutputMode::Text => {
+            println!("{:<22} {:<44} Status", "Option", "Address");
+            for check in checks {
+                // Pubkey's Display ignores the width, so format it first.
+                let address = check.address.to_string();
+                let status = if check.exists { "ok" } else { "MISSING" };
+                println!("{:<22} {:<44} {}", check.option, address, status);
+            }
+        }
+        OutputMode::Json => {
+            let json = serde_json::to_string_pretty(checks)
+                .expect("Serializing the checks to json does not fail.");
+            println!("{}", json);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake_rpc::{new_fake_rpc_client, test_account};
+    use crate::snapshot::{Config, SnapshotClient};
+
+    #[test]
+    fn check_accounts_reports_missing_accounts() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let existing = Pubkey::new_unique();
+        let missing = Pubkey::new_unique();
+        fake_state
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(existing, test_account(Pubkey::new_unique(), vec![]));
+
+        let mut config = Config {
+            client: SnapshotClient::new(rpc_client),
+        };
+        let checks = check_accounts(
+            &mut config,
+            &[("--watch-mint", existing), ("--watch-mint", missing)],
+        )
+        .ok()
+        .unwrap();
+
+        assert_eq!(
+            checks,
+            vec![
+                AccountCheck {
+                    option: "--watch-mint",
+                    address: existing,
+                    exists: true,
+                },
+                AccountCheck {
+                    option: "--watch-mint",
+                    address: missing,
+                    exists: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Integer fields at fixed offsets in account data, configured with `--custom-field`.
+//!
+//! This lets integrators monitor a field of an account with any layout,
+//! without writing Rust. A field is configured as
+//! `<name>=<account>:<offset>:<type>[:<scale>]`, for example
+//! `supply=<pubkey>:36:u64:0.000000001`. The integer is little-endian, and we
+//! report it multiplied by the scale, which defaults to 1.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+use solana_sdk::pubkey::Pubkey;
+
+const FORMAT_ERROR: &str = "Expected a field of the form name=account:offset:type[:scale].";
+
+/// Integer type of a custom field.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FieldType {
+    U32,
+    U64,
+    I64,
+}
+
+impl FieldType {
+    /// Size of the field in bytes.
+    fn size(self) -> usize {
+        match self {
+            FieldType::U32 => 4,
+            FieldType::U64 | FieldType::I64 => 8,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            FieldType::U32 => "u32",
+            FieldType::U64 => "u64",
+            FieldType::I64 => "i64",
+        }
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<FieldType, String> {
+        match s {
+            "u32" => Ok(FieldType::U32),
+            "u64" => Ok(FieldType::U64),
+            "i64" => Ok(FieldType::I64),
+            _ => Err(format!(
+                "Invalid field type '{}', expected 'u32', 'u64', or 'i64'.",
+                s
+            )),
+        }
+    }
+}
+
+/// A field to read from the data of an account.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomField {
+    /// Name of the field, which becomes the `name` label.
+    pub name: String,
+
+    /// The account that holds the field.
+    pub account: Pubkey,
+
+    /// Offset of the field in the account data, in bytes.
+    pub offset: usize,
+
+    pub type_: FieldType,
+
+    /// Factor to multiply the integer value with.
+    pub scale: f64,
+}
+
+impl CustomField {
+    /// Read the field from the account data, `None` if the data is too short to hold it.
+    pub fn read(&self, data: &[u8]) -> Option<f64> {
+        read_field(data, self.offset, self.type_).map(|value| value * self.scale)
+    }
+}
+
+/// Read a little-endian integer at `offset`, `None` if it does not fit in `data`.
+fn read_field(data: &[u8], offset: usize, type_: FieldType) -> Option<f64> {
+    let end = offset.checked_add(type_.size())?;
+    let bytes = data.get(offset..end)?;
+    let value = match type_ {
+        FieldType::U32 => u32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+        FieldType::U64 => u64::from_le_bytes(bytes.try_into().unwrap()) as f64,
+        FieldType::I64 => i64::from_le_bytes(bytes.try_into().unwrap()) as f64,
+    };
+    Some(value)
+}
+
+impl FromStr for CustomField {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CustomField, String> {
+        let (name, spec) = match s.split_once('=') {
+            Some((name, spec)) if !name.is_empty() => (name, spec),
+            _ => return Err(FORMAT_ERROR.to_string()),
+        };
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (account, offset, type_, scale) = match parts[..] {
+            [account, offset, type_] => (account, offset, type_, None),
+            [account, offset, type_, scale] => (account, offset, type_, Some(scale)),
+            _ => return Err(FORMAT_ERROR.to_string()),
+        };
+        let scale = match scale {
+            Some(scale) => scale
+                .parse::<f64>()
+                .map_err(|err| format!("Invalid scale '{}': {}", scale, err))?,
+            None => 1.0,
+        };
+        Ok(CustomField {
+            name: name.to_string(),
+            account: account
+                .parse()
+                .map_err(|err| format!("Invalid account '{}': {}", account, err))?,
+            offset: offset
+                .parse()
+                .map_err(|err| format!("Invalid offset '{}': {}", offset, err))?,
+            type_: type_.parse()?,
+            scale,
+        })
+    }
+}
+
+impl fmt::Display for CustomField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}={}:{}:{}:{}",
+            self.name,
+            self.account,
+            self.offset,
+            self.type_.as_str(),
+            self.scale
+        )
+    }
+}
+
+/// Serialize in the same format as the command-line option.
+impl Serialize for CustomField {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The value of a custom field, read from a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomFieldValue {
+    pub name: String,
+    pub account: Pubkey,
+    pub value: f64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_field_reads_little_endian_integers() {
+        let mut data = vec![0xff];
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(&(-42i64).to_le_bytes());
+        assert_eq!(read_field(&data, 1, FieldType::U32), Some(7.0));
+        assert_eq!(read_field(&data, 5, FieldType::I64), Some(-42.0));
+        assert_eq!(
+            read_field(&data, 5, FieldType::U64),
+            Some((u64::MAX - 41) as f64)
+        );
+    }
+
+    #[test]
+    fn read_field_rejects_out_of_range_offsets() {
+        let data = [0u8; 8];
+        assert_eq!(read_field(&data, 4, FieldType::U32), Some(0.0));
+        assert_eq!(read_field(&data, 5, FieldType::U32), None);
+        assert_eq!(read_field(&data, 1, FieldType::U64), None);
+        assert_eq!(read_field(&data, 8, FieldType::I64), None);
+        assert_eq!(read_field(&data, usize::MAX, FieldType::U32), None);
+    }
+
+    #[test]
+    fn custom_field_parses_and_applies_scale() {
+        let account = Pubkey::new_unique();
+        let field: CustomField = format!("supply={}:4:u32:0.5", account).parse().unwrap();
+        assert_eq!(
+            field,
+            CustomField {
+                name: "supply".to_string(),
+                account,
+                offset: 4,
+                type_: FieldType::U32,
+                scale: 0.5,
+            }
+        );
+        assert_eq!(field.read(&[0, 0, 0, 0, 3, 0, 0, 0]), Some(1.5));
+        assert_eq!(field.to_string().parse::<CustomField>(), Ok(field));
+
+        let field: CustomField = format!("counter={}:0:u64", account).parse().unwrap();
+        assert_eq!(field.scale, 1.0);
+
+        assert!(format!("={}:0:u64", account)
+            .parse::<CustomField>()
+            .is_err());
+        assert!(format!("x={}:0", account).parse::<CustomField>().is_err());
+        assert!(format!("x={}:-1:u64", account)
+            .parse::<CustomField>()
+            .is_err());
+        assert!(format!("x={}:0:u16", account)
+            .parse::<CustomField>()
+            .is_err());
+        assert!(format!("x={}:0:u64:half", account)
+            .parse::<CustomField>()
+            .is_err());
+        assert!("x=notapubkey:0:u64".parse::<CustomField>().is_err());
+    }
+}
@@ -1,11 +1,152 @@
 use std::{
-    sync::{Arc, Mutex},
+    collections::VecDeque,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant, SystemTime},
 };
 
-use crate::{snapshot::SnapshotClientConfig, Metrics, MetricsMutex, Opts};
+use crate::{
+    accounts_file::{diff_accounts, read_accounts_file},
+    block_production::BlockProductionSource,
+    custom_field::{CustomField, CustomFieldValue},
+    error::{Abort, Error},
+    fees::{BaseFeeSource, PrioritizationFeeSource},
+    gossip::GossipSource,
+    ledger::{LedgerRetentionSource, SnapshotSlotsSource},
+    lock_metrics,
+    metric_source::{CoreSource, CoreState, MetricSources},
+    panics::get_panic_count,
+    probe::{get_probe_timeout, ProbeSource},
+    pushgateway::{get_push_url, new_push_client, push_metrics, PUSHGATEWAY_JOB},
+    redact_url,
+    rent::get_account_rents,
+    rewards::get_inflation_rewards,
+    rpc_stats::RpcRequestCounts,
+    signature_status::SignatureSource,
+    slot_hashes::{get_slot_hashes_summary, SlotHashesSummary},
+    snapshot::{AccountSize, SnapshotAccounts, SnapshotClientConfig},
+    spl_token_utils::{
+        parse_mint, parse_token_account, TokenAccount, TokenAccountBalance, TokenHoldersSource,
+        TokenMint, TokenTopHoldersSource,
+    },
+    stake::{
+        get_latest_stake_history_totals, get_stake_activation, is_stake_account, StakeActivation,
+        StakeHistoryTotals,
+    },
+    textfile::write_textfile,
+    vote_accounts::{ClusterStakeSource, WatchedVoteAccountsSource},
+    watchdog::Heartbeat,
+    Metrics, MetricsMutex, Opts,
+};
 use rand::{rngs::ThreadRng, Rng};
-use solana_program::clock::Clock;
+use serde::Serialize;
+use solana_program::clock::{Clock, Epoch, Slot, UnixTimestamp};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::read_keypair_file;
+use solana_sdk::sysvar::{epoch_schedule::EpochSchedule, rent::Rent};
+use url::Url;
+
+/// Below this rate of slots per second, we consider the slot not to be advancing.
+///
+/// A healthy cluster produces a slot every 400ms, so 2.5 slots per second.
+const STALLED_MAX_SLOTS_PER_SECOND: f64 = 0.1;
+
+/// Number of consecutive polls without slot progress before we report a stall.
+///
+/// A single poll without progress can happen legitimately, e.g. when the poll
+/// interval is short, or due to a few skipped slots.
+const STALLED_MIN_POLLS: u32 = 3;
+
+/// Seconds per slot to estimate the time until the next epoch with, until we observed a rate.
+const DEFAULT_SECONDS_PER_SLOT: f64 = 0.4;
+
+/// Minimum time to sleep after an error, before retrying.
+pub const MIN_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Maximum time to sleep after an error, no matter how long the errors persist.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Minimum time to sleep after the RPC node rate limited us.
+///
+/// The sender already retried the request a few times, so the rate limit is
+/// not a short burst, and retrying soon would only use up more of the quota.
+const RATE_LIMITED_MIN_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Factor by which we sleep longer after the RPC node rate limited us, than after other errors.
+const RATE_LIMITED_BACKOFF_FACTOR: u32 = 4;
+
+/// Number of consecutive successful and failed polls.
+///
+/// At most one of the two is nonzero.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct PollStreak {
+    consecutive_successes: u64,
+    consecutive_errors: u64,
+}
+
+impl PollStreak {
+    fn record_success(&mut self) {
+        self.consecutive_successes += 1;
+        self.consecutive_errors = 0;
+    }
+
+    fn record_error(&mut self) {
+        self.consecutive_errors += 1;
+        self.consecutive_successes = 0;
+    }
+}
+
+/// Cumulative time that the daemon spent polling and sleeping.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct TimeSpent {
+    /// Time spent in polls, including the metric sources and publishing the metrics.
+    polling: Duration,
+
+    /// Time spent sleeping between polls.
+    sleeping: Duration,
+}
+
+impl TimeSpent {
+    fn record_poll(&mut self, duration: Duration) {
+        self.polling += duration;
+    }
+
+    fn record_sleep(&mut self, duration: Duration) {
+        self.sleeping += duration;
+    }
+}
+
+/// Durations of the most recent polls, oldest first.
+#[derive(Debug, Eq, PartialEq)]
+struct PollDurations {
+    durations: VecDeque<Duration>,
+
+    /// Number of polls to keep.
+    window: usize,
+}
+
+impl PollDurations {
+    fn new(window: usize) -> PollDurations {
+        PollDurations {
+            durations: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    fn record(&mut self, duration: Duration) {
+        if self.durations.len() == self.window {
+            self.durations.pop_front();
+        }
+        self.durations.push_back(duration);
+    }
+
+    fn as_seconds(&self) -> Vec<f64> {
+        self.durations.iter().map(Duration::as_secs_f64).collect()
+    }
+}
 
 pub struct Daemon<'a> {
     pub config: &'a mut SnapshotClientConfig<'a>,
@@ -15,6 +156,9 @@ pub struct Daemon<'a> {
     pub rng: ThreadRng,
 
     /// The instant after we successfully queried the on-chain state for the last time.
+    ///
+    /// Only the snapshot counts, failing metric sources back off by
+    /// themselves, so they do not delay the snapshot.
     pub last_read_success: Instant,
 
     /// Metrics counters to track status.
@@ -22,23 +166,121 @@ pub struct Daemon<'a> {
 
     /// Mutex where we publish the latest snapshot for use by the webserver.
     pub snapshot_mutex: Arc<MetricsMutex>,
+
+    /// The slot observed in the previous successful poll, and when we observed it.
+    previous_slot: Option<(Slot, Instant)>,
+
+    /// Number of consecutive polls in which the slot did not advance.
+    stalled_polls: u32,
+
+    /// The epoch observed in the previous successful poll.
+    previous_epoch: Option<Epoch>,
+
+    /// Whether we should read the inflation rewards, at start and after every epoch rollover.
+    inflation_rewards_due: bool,
+
+    /// Number of consecutive successful and failed base polls.
+    streak: PollStreak,
+
+    /// Time spent polling and sleeping since start.
+    time_spent: TimeSpent,
+
+    /// Durations of the last `--poll-duration-window` polls.
+    recent_polls: PollDurations,
+
+    /// When the current poll started, for the `--watchdog-seconds` watchdog.
+    pub heartbeat: Arc<Heartbeat>,
+
+    /// Metric sources, polled after every successful snapshot, each at its own interval.
+    metric_sources: MetricSources<'a>,
+
+    /// Number of requests made by the RPC client, updated by its transport.
+    rpc_request_counts: Arc<RpcRequestCounts>,
+
+    /// Whether the RPC node rate limited us during the last failed poll.
+    rate_limited: bool,
+
+    /// Whether a poll read all watched accounts, since start or since we added accounts.
+    ///
+    /// Until then, with --publish-core-metrics-early, we publish the clock and
+    /// version before every poll, so they do not wait for the watched accounts.
+    accounts_resolved: bool,
+
+    /// Url to push the metrics to after every successful poll, if any.
+    pushgateway_url: Option<Url>,
+
+    /// Client to push with, built once when we push at all.
+    pushgateway_client: Option<reqwest::blocking::Client>,
+
+    /// Accounts from `--accounts-file` to read in every snapshot.
+    watched_accounts: Vec<Pubkey>,
+
+    /// Set by the signal handler when we should reload the `--accounts-file`.
+    reload_requested: Arc<AtomicBool>,
+
+    /// Accounts of `--watch-stake` that the previous snapshot omitted.
+    omitted_stake_accounts: Vec<Pubkey>,
+
+    /// Fields of `--custom-field` that the previous snapshot omitted.
+    omitted_custom_fields: Vec<&'a CustomField>,
 }
 
-struct RpcData {
+struct RpcData<'a> {
     clock: Clock,
     version: String,
+    feature_set: Option<u32>,
+    token_mints: Vec<TokenMint>,
+    token_accounts: Vec<TokenAccount>,
+    account_sizes: Vec<AccountSize>,
+    rent: Rent,
+    epoch_schedule: EpochSchedule,
+
+    /// Accounts of `--watch-stake` that are stake accounts.
+    stake_accounts: Vec<Pubkey>,
+
+    /// Accounts of `--watch-stake` that are not stake accounts, or that do not exist.
+    omitted_stake_accounts: Vec<Pubkey>,
+
+    /// Totals of the most recent epoch in the stake history, with `--stake-history`.
+    stake_history: Option<StakeHistoryTotals>,
+
+    /// Extent of the `SlotHashes` sysvar, with `--slot-hashes`.
+    slot_hashes: Option<SlotHashesSummary>,
+
+    /// All accounts in the snapshot, with `--debug-snapshot-token-file`.
+    snapshot_accounts: Option<Arc<SnapshotAccounts>>,
+
+    account_data_hashes: Vec<(Pubkey, u64)>,
+    custom_fields: Vec<CustomFieldValue>,
+
+    /// Fields of `--custom-field` whose account does not exist or is too small.
+    omitted_custom_fields: Vec<&'a CustomField>,
 }
 
 impl<'a> Daemon<'a> {
-    pub fn new(config: &'a mut SnapshotClientConfig<'a>, opts: &'a Opts) -> Self {
+    pub fn new(
+        config: &'a mut SnapshotClientConfig<'a>,
+        opts: &'a Opts,
+        rpc_request_counts: Arc<RpcRequestCounts>,
+        reload_requested: Arc<AtomicBool>,
+    ) -> Self {
         let metrics = Metrics {
-            current_slot: 0,
-            current_epoch: 0,
-            solana_version: "0.0.0".to_owned(),
-            polls: 0,
-            errors: 0,
-            produced_at: SystemTime::UNIX_EPOCH,
+            rpc_cluster: Some(redact_url(&opts.cluster)),
+            ..Metrics::default()
+        };
+        let pushgateway_url = Daemon::get_pushgateway_url(opts);
+        let pushgateway_client = pushgateway_url.as_ref().map(|_| {
+            new_push_client(Duration::from_secs(opts.http_timeout_seconds as u64))
+                .ok_or_abort_with("Failed to set up the Pushgateway client.")
+        });
+        let watched_accounts = match &opts.accounts_file {
+            Some(path) => {
+                read_accounts_file(path).ok_or_abort_with("Failed to read --accounts-file.")
+            }
+            None => Vec::new(),
         };
+        // We know these accounts upfront, so the first poll need not discover them.
+        config.client.preload(&watched_accounts);
         Daemon {
             config,
             opts,
@@ -46,59 +288,767 @@ impl<'a> Daemon<'a> {
             last_read_success: Instant::now(),
             metrics: metrics.clone(),
             snapshot_mutex: Arc::new(Mutex::new(Arc::new(metrics))),
+            previous_slot: None,
+            stalled_polls: 0,
+            previous_epoch: None,
+            inflation_rewards_due: true,
+            streak: PollStreak::default(),
+            time_spent: TimeSpent::default(),
+            recent_polls: PollDurations::new(opts.poll_duration_window),
+            heartbeat: Arc::new(Heartbeat::default()),
+            metric_sources: Daemon::get_metric_sources(opts),
+            rpc_request_counts,
+            rate_limited: false,
+            accounts_resolved: false,
+            pushgateway_url,
+            pushgateway_client,
+            watched_accounts,
+            reload_requested,
+            omitted_stake_accounts: Vec::new(),
+            omitted_custom_fields: Vec::new(),
         }
     }
 
-    fn get_sleep_time_after_error(&mut self) -> Duration {
-        // For the sleep time we use exponential backoff with jitter [1]. By taking
-        // the time since the last success as the target sleep time, we get
-        // exponential backoff. We clamp this to ensure we don't wait indefinitely.
-        // 1: https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
-        let time_since_last_success = self.last_read_success.elapsed();
-        let min_sleep_time = Duration::from_secs_f32(0.2);
-        let max_sleep_time = Duration::from_secs_f32(300.0);
-        let target_sleep_time = time_since_last_success.clamp(min_sleep_time, max_sleep_time);
-        let sleep_time = self
-            .rng
-            .gen_range(Duration::from_secs(0)..target_sleep_time);
-        println!("Sleeping {:?} after error ...", sleep_time);
-        sleep_time
+    fn get_pushgateway_url(opts: &Opts) -> Option<Url> {
+        let base = opts.pushgateway.as_ref()?;
+        let instance = match &opts.pushgateway_instance {
+            Some(instance) => instance.clone(),
+            None => {
+                let mut buffer = [0u8; 256];
+                nix::unistd::gethostname(&mut buffer)
+                    .ok_or_abort_with(
+                        "Failed to get the hostname, please set --pushgateway-instance.",
+                    )
+                    .to_string_lossy()
+                    .into_owned()
+            }
+        };
+        let url = get_push_url(base, PUSHGATEWAY_JOB, &instance)
+            .ok_or_abort_with("Invalid --pushgateway url.");
+        Some(url)
     }
 
-    pub fn run(&mut self) -> ! {
-        loop {
-            self.metrics.polls += 1;
-            let sleep_time = match self.config.with_snapshot(|config| {
-                let clock = config.client.get_clock()?;
-                let version = config.client.get_version()?;
-                Ok(RpcData {
-                    clock,
-                    version: version.solana_core,
-                })
-            }) {
-                Ok(rpc_data) => {
-                    // Update metrics from RPC.
-                    self.metrics.current_slot = rpc_data.clock.slot;
-                    self.metrics.current_epoch = rpc_data.clock.epoch;
-                    self.metrics.solana_version = rpc_data.version;
-                    self.metrics.produced_at = SystemTime::now();
-
-                    // Update metrics snapshot.
-                    *self.snapshot_mutex.lock().unwrap() = Arc::new(self.metrics.clone());
-                    std::time::Duration::from_secs(self.opts.poll_interval_seconds as u64)
+    /// Register the enabled metric sources, unless --disable-metric disables all of their families.
+    ///
+    /// The core source comes first, the others follow in the order of their options.
+    fn get_metric_sources(opts: &'a Opts) -> MetricSources<'a> {
+        let seconds = |seconds: u32| Duration::from_secs(seconds as u64);
+        let mut sources = MetricSources::default();
+        sources.register(Box::new(CoreSource));
+        if let Some(identity) = opts.leader_identity {
+            sources.register(Box::new(BlockProductionSource::new(
+                identity,
+                opts.block_production_slots,
+                opts.leader_slots,
+                seconds(opts.block_production_interval_seconds),
+            )));
+        }
+        if let (Some(identity), true) = (opts.leader_identity, opts.gossip_status) {
+            sources.register(Box::new(GossipSource {
+                identity,
+                interval: seconds(opts.cluster_nodes_interval_seconds),
+            }));
+        }
+        if opts.prioritization_fees {
+            sources.register(Box::new(PrioritizationFeeSource {
+                accounts: &opts.fee_account,
+            }));
+        }
+        if opts.cluster_stake {
+            sources.register(Box::new(ClusterStakeSource {
+                interval: seconds(opts.vote_accounts_interval_seconds),
+            }));
+        }
+        if !opts.watch_vote_account.is_empty() {
+            sources.register(Box::new(WatchedVoteAccountsSource {
+                vote_accounts: &opts.watch_vote_account,
+                interval: opts
+                    .watch_vote_account_interval_seconds
+                    .map_or(Duration::ZERO, seconds),
+            }));
+        }
+        if opts.ledger_retention {
+            sources.register(Box::new(LedgerRetentionSource {
+                interval: seconds(opts.ledger_retention_interval_seconds),
+            }));
+        }
+        if opts.snapshot_slots {
+            sources.register(Box::new(SnapshotSlotsSource));
+        }
+        if !opts.count_token_holders.is_empty() {
+            sources.register(Box::new(TokenHoldersSource {
+                mints: &opts.count_token_holders,
+                interval: seconds(opts.token_holders_interval_seconds),
+            }));
+        }
+        if !opts.mint_top_holders.is_empty() {
+            sources.register(Box::new(TokenTopHoldersSource {
+                mints: &opts.mint_top_holders,
+                interval: seconds(opts.token_holders_interval_seconds),
+            }));
+        }
+        if let Some(path) = &opts.probe_keypair {
+            let keypair =
+                read_keypair_file(path).ok_or_abort_with("Failed to read --probe-keypair.");
+            sources.register(Box::new(ProbeSource {
+                keypair,
+                interval: seconds(opts.probe_interval_seconds),
+                timeout: get_probe_timeout(opts.watchdog_seconds.map(seconds)),
+            }));
+        }
+        if opts.base_fee {
+            sources.register(Box::new(BaseFeeSource));
+        }
+        if !opts.track_signature.is_empty() {
+            sources.register(Box::new(SignatureSource::new(&opts.track_signature)));
+        }
+        sources.retain(|source| opts.is_any_metric_enabled(source.family_names()));
+        sources
+    }
+
+    /// Update the slot advance rate and stall detection with a newly observed slot.
+    ///
+    /// A node that is stuck, or stuck on a fork, can still respond to RPC
+    /// calls just fine, but its slot stops advancing.
+    fn observe_slot(&mut self, slot: Slot) {
+        let now = Instant::now();
+        if let Some((previous_slot, previous_instant)) = self.previous_slot {
+            let rate = get_slot_advance_rate(previous_slot, slot, now - previous_instant);
+            if rate < STALLED_MAX_SLOTS_PER_SECOND {
+                self.stalled_polls += 1;
+            } else {
+                self.stalled_polls = 0;
+            }
+            self.metrics.slot_advance_rate = Some(rate);
+            self.metrics.slot_stalled = self.stalled_polls >= STALLED_MIN_POLLS;
+        }
+        self.previous_slot = Some((slot, now));
+    }
+
+    /// Poll the metric sources that are due, with the core state of the latest poll.
+    ///
+    /// A failing source counts as an error, but it does not fail the base
+    /// poll, and it keeps its last successful values.
+    fn poll_metric_sources(&mut self) {
+        let core = match &self.metrics.core {
+            Some(core) => core,
+            None => return,
+        };
+        let errors = self.metric_sources.poll_due(
+            &self.config.client,
+            core,
+            self.opts.backoff_jitter,
+            &mut self.rng,
+        );
+        for (name, err) in errors {
+            log::error!("Error while polling the {} metrics.", name);
+            err.print_pretty();
+            self.metrics.errors += 1;
+        }
+        self.metrics.source_families = self.metric_sources.get_metric_families();
+    }
+
+    /// Warn about `--watch-stake` accounts and `--custom-field`s that we omit.
+    ///
+    /// We only warn when one starts to be omitted, so a misconfigured address
+    /// does not log a warning on every poll.
+    fn observe_omissions(
+        &mut self,
+        stake_accounts: Vec<Pubkey>,
+        custom_fields: Vec<&'a CustomField>,
+    ) {
+        for address in &stake_accounts {
+            if !self.omitted_stake_accounts.contains(address) {
+                log::warn!("Account {} is not a stake account, omitting it.", address);
+            }
+        }
+        for field in &custom_fields {
+            if !self.omitted_custom_fields.contains(field) {
+                log::warn!(
+                    "Account {} does not hold --custom-field {}, omitting it.",
+                    field.account,
+                    field.name,
+                );
+            }
+        }
+        self.omitted_stake_accounts = stake_accounts;
+        self.omitted_custom_fields = custom_fields;
+    }
+
+    /// Count and log epoch transitions.
+    fn observe_epoch(&mut self, epoch: Epoch) {
+        if let Some(previous_epoch) = self.previous_epoch {
+            match get_epoch_change(previous_epoch, epoch) {
+                EpochChange::Unchanged => {}
+                EpochChange::Advanced => {
+                    self.metrics.epoch_transitions += 1;
+                    self.inflation_rewards_due = true;
+                    log::info!("Epoch advanced from {} to {}.", previous_epoch, epoch);
+                }
+                EpochChange::WentBackwards => {
+                    log::warn!(
+                        "Epoch went backwards from {} to {}, was the RPC node rolled back?",
+                        previous_epoch,
+                        epoch
+                    );
                 }
+            }
+        }
+        self.previous_epoch = Some(epoch);
+    }
+
+    /// Read the inflation rewards of the previous epoch, if they are due.
+    ///
+    /// On failure, they remain due, so we try again in the next poll.
+    fn poll_inflation_rewards_if_due(&mut self) {
+        if !self.inflation_rewards_due || self.opts.reward_account.is_empty() {
+            return;
+        }
+        // There are no rewards before the first epoch ended.
+        let current_epoch = self
+            .metrics
+            .core
+            .as_ref()
+            .map_or(0, |core| core.clock.epoch);
+        let epoch = match current_epoch.checked_sub(1) {
+            Some(epoch) => epoch,
+            None => return,
+        };
+        match get_inflation_rewards(
+            self.config.client.rpc_client(),
+            self.config.client.commitment(),
+            &self.opts.reward_account,
+            epoch,
+        ) {
+            Ok(rewards) => {
+                self.metrics.inflation_rewards = rewards;
+                self.inflation_rewards_due = false;
+            }
+            Err(err) => {
+                log::error!("Error while reading inflation rewards.");
+                err.print_pretty();
+                self.metrics.errors += 1;
+            }
+        }
+    }
+
+    /// Make the current metrics available to the http server and the textfile.
+    fn publish_metrics(&mut self) {
+        self.metrics.rpc_requests = self.rpc_request_counts.lock_by_method().clone();
+        self.metrics.rpc_rate_limited =
+            self.rpc_request_counts.rate_limited.load(Ordering::Relaxed);
+        self.metrics.chunked_reads = self.config.client.chunked_reads();
+        if self.opts.verify_consistency {
+            self.metrics.inconsistent_snapshots = Some(self.config.client.inconsistent_snapshots());
+        }
+        self.metrics.panics = get_panic_count();
+        self.metrics.validator_info_reloads = self.config.client.validator_info_reloads();
+        self.metrics.consecutive_successes = self.streak.consecutive_successes;
+        self.metrics.consecutive_errors = self.streak.consecutive_errors;
+        self.metrics.poll_seconds = self.time_spent.polling.as_secs_f64();
+        self.metrics.sleep_seconds = self.time_spent.sleeping.as_secs_f64();
+        self.metrics.recent_poll_seconds = self.recent_polls.as_seconds();
+
+        *lock_metrics(&self.snapshot_mutex) = Arc::new(self.metrics.clone());
+        // Like the http server, do not expose zeroes before the first success.
+        if let (Some(path), true) = (&self.opts.textfile, self.metrics.ready) {
+            let options = self.opts.get_write_options();
+            if let Err(err) = write_textfile(path, &self.metrics, &options) {
+                log::error!("{}\nFailed to write metrics to {}.", err, path.display());
+            }
+        }
+    }
+
+    /// Reload the `--accounts-file`, if a reload was requested.
+    ///
+    /// We update the accounts to query right away, so the next snapshot does
+    /// not need a retry for the added accounts. If the file cannot be read, we
+    /// keep monitoring the current accounts.
+    fn reload_accounts_file_if_requested(&mut self) {
+        if !self.reload_requested.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let path = match &self.opts.accounts_file {
+            Some(path) => path,
+            None => return,
+        };
+        let accounts = match read_accounts_file(path) {
+            Ok(accounts) => accounts,
+            Err(err) => {
+                log::error!(
+                    "Failed to reload {}, keeping the current accounts.",
+                    path.display()
+                );
+                err.print_pretty();
+                self.metrics.errors += 1;
+                return;
+            }
+        };
+        let diff = diff_accounts(&self.watched_accounts, &accounts);
+        for address in &diff.removed {
+            self.config.client.forget_account(address);
+        }
+        self.config.client.preload(&diff.added);
+        if !diff.added.is_empty() {
+            self.accounts_resolved = false;
+        }
+        self.watched_accounts = accounts;
+        self.metrics.config_reloads += 1;
+        log::info!(
+            "Reloaded {}, {} accounts added, {} removed.",
+            path.display(),
+            diff.added.len(),
+            diff.removed.len(),
+        );
+    }
+
+    /// Set the metrics that do not depend on the watched accounts.
+    fn update_core_metrics(&mut self, clock: &Clock, version: String, feature_set: Option<u32>) {
+        let produced_at = SystemTime::now();
+        self.metrics.core = Some(CoreState {
+            clock: clock.clone(),
+            version,
+            feature_set,
+            produced_at,
+        });
+        self.metrics.produced_at = produced_at;
+        self.metrics.clock_drift_seconds =
+            get_clock_drift_seconds(clock.unix_timestamp, produced_at);
+    }
+
+    /// Read the clock and version, and publish them with the metric sources,
+    /// before the watched accounts resolve.
+    ///
+    /// This reads the clock outside of the snapshot, so it does not depend on
+    /// how many iterations the snapshot needs to discover the watched accounts.
+    fn publish_core_metrics(&mut self) {
+        let client = &self.config.client;
+        let result = client
+            .get_clock_now()
+            .and_then(|clock| Ok((clock, client.rpc_client().get_version()?)));
+        match result {
+            Ok((clock, version)) => {
+                self.update_core_metrics(&clock, version.solana_core, version.feature_set);
+                self.poll_metric_sources();
+                self.metrics.ready = true;
+                self.publish_metrics();
+            }
+            Err(err) => {
+                log::error!("Error while reading the clock and version.");
+                err.print_pretty();
+                self.metrics.errors += 1;
+            }
+        }
+    }
+
+    /// Call `getStakeActivation` for every stake account, outside of the snapshot.
+    ///
+    /// A failed call counts as an error, and omits the account, but does not
+    /// fail the poll.
+    fn get_stake_activations(&mut self, stake_accounts: &[Pubkey]) -> Vec<StakeActivation> {
+        let mut activations = Vec::with_capacity(stake_accounts.len());
+        for stake_account in stake_accounts {
+            let client = &self.config.client;
+            match get_stake_activation(client.rpc_client(), client.commitment(), *stake_account) {
+                Ok(activation) => activations.push(activation),
                 Err(err) => {
-                    println!("Error while obtaining on-chain state.");
+                    log::error!(
+                        "Failed to get the activation of stake account {}.",
+                        stake_account
+                    );
                     err.print_pretty();
                     self.metrics.errors += 1;
-                    self.get_sleep_time_after_error()
                 }
+            }
+        }
+        activations
+    }
+
+    /// Push the current metrics to the Pushgateway, if configured.
+    ///
+    /// A failed push counts as an error, but it does not affect the poll
+    /// itself, we push again after the next successful poll.
+    fn push_metrics(&mut self) {
+        if let (Some(url), Some(client)) = (&self.pushgateway_url, &self.pushgateway_client) {
+            let options = self.opts.get_write_options();
+            if let Err(err) = push_metrics(client, url, &self.metrics, &options) {
+                log::error!("Failed to push metrics to the Pushgateway.");
+                err.print_pretty();
+                self.metrics.errors += 1;
+            }
+        }
+    }
+
+    fn get_sleep_time_after_error(&mut self) -> Duration {
+        let sleep_time = get_backoff_sleep_time(
+            self.opts.backoff_jitter,
+            self.last_read_success.elapsed(),
+            MIN_BACKOFF,
+            MAX_BACKOFF,
+            &mut self.rng,
+        );
+        if self.rate_limited {
+            let sleep_time = get_rate_limited_sleep_time(sleep_time);
+            log::debug!("Sleeping {:?} after being rate limited ...", sleep_time);
+            return sleep_time;
+        }
+        log::debug!("Sleeping {:?} after error ...", sleep_time);
+        sleep_time
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            let poll_start = Instant::now();
+            self.heartbeat.record_poll_start(poll_start);
+            let success = self.poll();
+            self.heartbeat.record_poll_end();
+            let sleep_time = if success {
+                Duration::from_secs(self.opts.poll_interval_seconds as u64)
+            } else {
+                self.get_sleep_time_after_error()
             };
+            // We published the metrics already, so these show up in the next publish.
+            let poll_duration = poll_start.elapsed();
+            self.time_spent.record_poll(poll_duration);
+            self.recent_polls.record(poll_duration);
             std::thread::sleep(sleep_time);
+            self.time_spent.record_sleep(sleep_time);
+        }
+    }
+
+    /// Poll once and publish the metrics, return whether the poll succeeded.
+    pub fn poll(&mut self) -> bool {
+        self.reload_accounts_file_if_requested();
+        self.metrics.polls += 1;
+        let rate_limited_before = self.rpc_request_counts.rate_limited.load(Ordering::Relaxed);
+        if self.opts.publish_core_metrics_early && !self.accounts_resolved {
+            self.publish_core_metrics();
+        }
+        let opts = self.opts;
+        let watched_accounts = &self.watched_accounts;
+        match self.config.with_snapshot(|config| {
+            let clock = config.client.get_clock()?;
+            let version = config.client.get_version()?;
+            // Read rent in the snapshot, so the rent-exempt minimum is
+            // consistent with the balances of the accounts.
+            let rent = config.client.get_rent()?;
+            let epoch_schedule = config.client.get_epoch_schedule()?;
+            let stake_history = if opts.stake_history {
+                get_latest_stake_history_totals(&config.client.get_stake_history()?)
+            } else {
+                None
+            };
+            let slot_hashes = if opts.slot_hashes {
+                get_slot_hashes_summary(&config.client.get_slot_hashes()?)
+            } else {
+                None
+            };
+            let mut token_mints = Vec::with_capacity(opts.watch_mint.len());
+            for address in &opts.watch_mint {
+                let account = match config
+                    .client
+                    .get_watched_account(address, opts.skip_missing_accounts)?
+                {
+                    Some(account) => account,
+                    None => continue,
+                };
+                token_mints.push(TokenMint {
+                    address: *address,
+                    supply: parse_mint(account),
+                });
+            }
+            let mut token_accounts = Vec::with_capacity(opts.watch_token_account.len());
+            for address in &opts.watch_token_account {
+                let account = match config
+                    .client
+                    .get_watched_account(address, opts.skip_missing_accounts)?
+                {
+                    Some(account) => account,
+                    None => continue,
+                };
+                // The balance is only meaningful with the decimals of the
+                // mint, read the mint in the same snapshot.
+                let balance = match parse_token_account(account) {
+                    Ok(token_account) => {
+                        let mint_account = match config
+                            .client
+                            .get_watched_account(&token_account.mint, opts.skip_missing_accounts)?
+                        {
+                            Some(mint_account) => mint_account,
+                            None => continue,
+                        };
+                        parse_mint(mint_account).map(|mint| TokenAccountBalance {
+                            mint: token_account.mint,
+                            owner: token_account.owner,
+                            amount: token_account.amount,
+                            decimals: mint.decimals,
+                            is_frozen: token_account.is_frozen(),
+                        })
+                    }
+                    Err(reason) => Err(reason),
+                };
+                token_accounts.push(TokenAccount {
+                    address: *address,
+                    balance,
+                });
+            }
+            // We may run this closure several times per poll, so we warn about
+            // omitted accounts and fields after it, see `observe_omissions`.
+            let mut stake_accounts = Vec::with_capacity(opts.watch_stake.len());
+            let mut omitted_stake_accounts = Vec::new();
+            for address in &opts.watch_stake {
+                match config.client.get_account_if_exists(address)? {
+                    Some(account) if is_stake_account(account) => stake_accounts.push(*address),
+                    _ => omitted_stake_accounts.push(*address),
+                }
+            }
+            let mut custom_fields = Vec::with_capacity(opts.custom_field.len());
+            let mut omitted_custom_fields = Vec::new();
+            for field in &opts.custom_field {
+                let value = match config.client.get_account_if_exists(&field.account)? {
+                    Some(account) => field.read(&account.data),
+                    None => None,
+                };
+                match value {
+                    Some(value) => custom_fields.push(CustomFieldValue {
+                        name: field.name.clone(),
+                        account: field.account,
+                        value,
+                    }),
+                    None => omitted_custom_fields.push(field),
+                }
+            }
+            // These only need to be referenced, they are reported through
+            // the account sizes.
+            for address in watched_accounts {
+                config.client.get_account_if_exists(address)?;
+            }
+            // Collect the sizes last, after we referenced all accounts.
+            let account_sizes = config.client.get_referenced_account_sizes();
+            let account_data_hashes = if opts.account_data_hash {
+                config.client.get_referenced_account_data_hashes()
+            } else {
+                Vec::new()
+            };
+            let snapshot_accounts = match opts.debug_snapshot_token_file {
+                Some(..) => Some(Arc::new(config.client.clone_accounts())),
+                None => None,
+            };
+            Ok(RpcData {
+                clock,
+                version: version.solana_core,
+                feature_set: version.feature_set,
+                token_mints,
+                token_accounts,
+                account_sizes,
+                rent,
+                epoch_schedule,
+                stake_accounts,
+                omitted_stake_accounts,
+                stake_history,
+                slot_hashes,
+                account_data_hashes,
+                custom_fields,
+                omitted_custom_fields,
+                snapshot_accounts,
+            })
+        }) {
+            Ok(result) => {
+                // Only the snapshot resets the backoff, the metric sources
+                // below track their own failures.
+                self.last_read_success = Instant::now();
+                let retries = result.retries() as u64;
+                self.metrics.snapshot_retries += retries;
+                self.metrics.snapshot_last_retries = retries;
+                self.metrics
+                    .snapshot_iterations
+                    .observe(result.iterations as u64);
+                self.metrics.accounts_queried = result.accounts_queried as u64;
+                self.metrics.accounts_referenced = result.accounts_referenced as u64;
+                let rpc_data = result.value;
+
+                // Update metrics from RPC.
+                self.accounts_resolved = true;
+                self.update_core_metrics(&rpc_data.clock, rpc_data.version, rpc_data.feature_set);
+                self.metrics.token_mints = rpc_data.token_mints;
+                self.metrics.token_accounts = rpc_data.token_accounts;
+                self.metrics.account_rents =
+                    get_account_rents(&rpc_data.rent, &rpc_data.account_sizes);
+                self.metrics.account_sizes = rpc_data.account_sizes;
+                self.metrics.account_data_hashes = rpc_data.account_data_hashes;
+                self.metrics.custom_fields = rpc_data.custom_fields;
+                self.metrics.stake_history = rpc_data.stake_history;
+                self.metrics.slot_hashes = rpc_data.slot_hashes;
+                self.metrics.epoch_schedule = Some(rpc_data.epoch_schedule);
+                self.metrics.snapshot_accounts = rpc_data.snapshot_accounts;
+                self.observe_slot(rpc_data.clock.slot);
+                self.metrics.epoch_seconds_remaining = get_epoch_seconds_remaining(
+                    &rpc_data.epoch_schedule,
+                    rpc_data.clock.slot,
+                    self.metrics.slot_advance_rate,
+                );
+                self.observe_epoch(rpc_data.clock.epoch);
+                self.poll_inflation_rewards_if_due();
+                self.observe_omissions(
+                    rpc_data.omitted_stake_accounts,
+                    rpc_data.omitted_custom_fields,
+                );
+                self.metrics.stake_activations =
+                    self.get_stake_activations(&rpc_data.stake_accounts);
+                self.poll_metric_sources();
+                self.streak.record_success();
+                self.metrics.ready = true;
+                self.publish_metrics();
+                self.push_metrics();
+                true
+            }
+            Err(err) => {
+                log::error!("Error while obtaining on-chain state.");
+                err.print_pretty();
+                self.metrics.errors += 1;
+                self.streak.record_error();
+                self.rate_limited = self.rpc_request_counts.rate_limited.load(Ordering::Relaxed)
+                    > rate_limited_before;
+                if self.rate_limited {
+                    log::warn!("The RPC node is rate limiting us, backing off for longer.");
+                }
+                // Publish after errors too, so the error counters are
+                // visible while polls keep failing.
+                self.publish_metrics();
+                false
+            }
         }
     }
 }
 
+/// How to randomize the sleep time after an error.
+///
+/// See <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackoffJitter {
+    /// Sleep anywhere between zero and the target. This spreads retries best,
+    /// but some retries follow the error almost immediately.
+    Full,
+
+    /// Sleep at least half of the target. This avoids very short sleeps, at
+    /// the cost of spreading retries less.
+    Equal,
+
+    /// Sleep between the minimum and three times the target, capped at the
+    /// maximum. This grows faster than the other strategies.
+    Decorrelated,
+}
+
+impl FromStr for BackoffJitter {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<BackoffJitter, &'static str> {
+        match s {
+            "full" => Ok(BackoffJitter::Full),
+            "equal" => Ok(BackoffJitter::Equal),
+            "decorrelated" => Ok(BackoffJitter::Decorrelated),
+            _ => Err("Invalid backoff jitter, expected 'full', 'equal', or 'decorrelated'."),
+        }
+    }
+}
+
+/// Return how long to sleep after an error, with exponential backoff and jitter.
+///
+/// By taking the time since the last success as the target sleep time, we get
+/// exponential backoff. We clamp this between `min_sleep_time` and
+/// `max_sleep_time` to ensure we don't wait indefinitely.
+pub fn get_backoff_sleep_time<R: Rng>(
+    jitter: BackoffJitter,
+    time_since_last_success: Duration,
+    min_sleep_time: Duration,
+    max_sleep_time: Duration,
+    rng: &mut R,
+) -> Duration {
+    let target_sleep_time = time_since_last_success.clamp(min_sleep_time, max_sleep_time);
+    match jitter {
+        BackoffJitter::Full => rng.gen_range(Duration::from_secs(0)..target_sleep_time),
+        BackoffJitter::Equal => {
+            let half = target_sleep_time / 2;
+            half + rng.gen_range(Duration::from_secs(0)..=half)
+        }
+        BackoffJitter::Decorrelated => {
+            let upper = (target_sleep_time * 3).min(max_sleep_time);
+            rng.gen_range(min_sleep_time..=upper)
+        }
+    }
+}
+
+/// Return how long to sleep after the RPC node rate limited us, given the sleep after other errors.
+fn get_rate_limited_sleep_time(sleep_time: Duration) -> Duration {
+    (sleep_time * RATE_LIMITED_BACKOFF_FACTOR).clamp(RATE_LIMITED_MIN_BACKOFF, MAX_BACKOFF)
+}
+
+/// How the epoch changed between two successful polls.
+#[derive(Debug, Eq, PartialEq)]
+enum EpochChange {
+    Unchanged,
+    Advanced,
+
+    /// The epoch decreased, which can happen when the RPC node was restarted
+    /// from an older snapshot, or when we connect to a different node. This
+    /// does not count as a transition, the next advance does again.
+    WentBackwards,
+}
+
+fn get_epoch_change(previous_epoch: Epoch, epoch: Epoch) -> EpochChange {
+    match epoch.cmp(&previous_epoch) {
+        std::cmp::Ordering::Equal => EpochChange::Unchanged,
+        std::cmp::Ordering::Greater => EpochChange::Advanced,
+        std::cmp::Ordering::Less => EpochChange::WentBackwards,
+    }
+}
+
+/// Return how many seconds our clock is ahead of the cluster clock.
+///
+/// The cluster estimates `unix_timestamp` from the votes of validators, with a
+/// resolution of one second, so a drift of a few seconds is normal. A large
+/// positive drift means that the node is lagging behind.
+fn get_clock_drift_seconds(unix_timestamp: UnixTimestamp, now: SystemTime) -> f64 {
+    let now_seconds = match now.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(err) => -err.duration().as_secs_f64(),
+    };
+    now_seconds - unix_timestamp as f64
+}
+
+/// Return the number of slots per second that the slot advanced by.
+///
+/// If the slot went backwards (e.g. because the RPC node was restarted from an
+/// older snapshot), the rate is 0 rather than negative.
+fn get_slot_advance_rate(previous_slot: Slot, slot: Slot, elapsed: Duration) -> f64 {
+    let elapsed_seconds = elapsed.as_secs_f64();
+    if elapsed_seconds <= 0.0 {
+        return 0.0;
+    }
+    slot.saturating_sub(previous_slot) as f64 / elapsed_seconds
+}
+
+/// Return the estimated number of seconds until the epoch that `slot` is in ends.
+///
+/// We estimate the duration of a slot from the slot advance rate. Before we
+/// observed a rate, and while the slot is not advancing, we assume the target
+/// slot time of 400ms instead. The epoch schedule accounts for the shorter
+/// epochs during warmup.
+fn get_epoch_seconds_remaining(
+    epoch_schedule: &EpochSchedule,
+    slot: Slot,
+    slot_advance_rate: Option<f64>,
+) -> f64 {
+    let (epoch, slot_index) = epoch_schedule.get_epoch_and_slot_index(slot);
+    let slots_remaining = epoch_schedule.get_slots_in_epoch(epoch) - slot_index;
+    let seconds_per_slot = match slot_advance_rate {
+        Some(rate) if rate >= STALLED_MAX_SLOTS_PER_SECOND => 1.0 / rate,
+        _ => DEFAULT_SECONDS_PER_SLOT,
+    };
+    slots_remaining as f64 * seconds_per_slot
+}
+
 // fn get_metrics_from_solana_rpc(config: &mut SnapshotClientConfig, opts: &Opts) -> ListenerResult {
 //     let result = config.with_snapshot(|config| {
 //         let clock = config.client.get_clock()?;
@@ -110,3 +1060,329 @@ impl<'a> Daemon<'a> {
 //         Ok(clock) => {}
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use rand::{rngs::StdRng, SeedableRng};
+    use solana_client::rpc_client::RpcClient;
+    use solana_sdk::sysvar;
+
+    use super::*;
+    use crate::fake_rpc::{new_fake_rpc_client, test_account};
+    use crate::snapshot::Config;
+    use crate::SnapshotClient;
+
+    #[test]
+    fn get_metric_sources_skips_sources_with_all_metrics_disabled() {
+        // Only the core source.
+        let opts = Opts::parse_from(["solana-hydrant"]);
+        assert_eq!(Daemon::get_metric_sources(&opts).len(), 1);
+
+        let mint = Pubkey::new_unique().to_string();
+        let opts = Opts::parse_from([
+            "solana-hydrant",
+            "--count-token-holders",
+            &mint,
+            "--mint-top-holders",
+            &mint,
+        ]);
+        assert_eq!(Daemon::get_metric_sources(&opts).len(), 3);
+
+        let opts = Opts::parse_from([
+            "solana-hydrant",
+            "--count-token-holders",
+            &mint,
+            "--mint-top-holders",
+            &mint,
+            "--disable-metric",
+            "solana_token_holders_total",
+        ]);
+        assert_eq!(Daemon::get_metric_sources(&opts).len(), 2);
+
+        let opts = Opts::parse_from(["solana-hydrant", "--base-fee"]);
+        assert_eq!(Daemon::get_metric_sources(&opts).len(), 2);
+        let opts = Opts::parse_from([
+            "solana-hydrant",
+            "--base-fee",
+            "--disable-metric",
+            "solana_lamports_per_signature",
+        ]);
+        assert_eq!(Daemon::get_metric_sources(&opts).len(), 1);
+
+        // The watched vote accounts do not share a source with the cluster stake.
+        let vote_account = Pubkey::new_unique().to_string();
+        let opts = Opts::parse_from([
+            "solana-hydrant",
+            "--cluster-stake",
+            "--watch-vote-account",
+            &vote_account,
+        ]);
+        assert_eq!(Daemon::get_metric_sources(&opts).len(), 3);
+        let opts = Opts::parse_from([
+            "solana-hydrant",
+            "--cluster-stake",
+            "--watch-vote-account",
+            &vote_account,
+            "--disable-metric",
+            "solana_vote_delinquent",
+            "--disable-metric",
+            "solana_vote_last_vote_slot_lag",
+        ]);
+        assert_eq!(Daemon::get_metric_sources(&opts).len(), 2);
+    }
+
+    /// Return a fake RPC client with the sysvars that every poll reads.
+    fn new_fake_rpc_client_with_sysvars(clock: &Clock) -> RpcClient {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let mut state = fake_state.lock().unwrap();
+        let sysvars = [
+            (sysvar::clock::id(), bincode::serialize(clock)),
+            (sysvar::rent::id(), bincode::serialize(&Rent::default())),
+            (
+                sysvar::epoch_schedule::id(),
+                bincode::serialize(&EpochSchedule::default()),
+            ),
+        ];
+        for (address, data) in sysvars {
+            state
+                .accounts
+                .insert(address, test_account(sysvar::id(), data.unwrap()));
+        }
+        rpc_client
+    }
+
+    #[test]
+    fn poll_resets_backoff_when_only_a_metric_source_fails() {
+        let rpc_client = new_fake_rpc_client_with_sysvars(&Clock::default());
+
+        // The fake RPC does not support the base fee, so that source fails.
+        // Decorrelated jitter retries it no sooner than 0.2 seconds later.
+        let opts = Opts::parse_from([
+            "solana-hydrant",
+            "--base-fee",
+            "--backoff-jitter",
+            "decorrelated",
+        ]);
+        let mut config = Config {
+            client: SnapshotClient::new(rpc_client),
+        };
+        let mut daemon = Daemon::new(
+            &mut config,
+            &opts,
+            Arc::new(RpcRequestCounts::default()),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let failing_since = Instant::now() - Duration::from_secs(60);
+        daemon.last_read_success = failing_since;
+
+        assert!(daemon.poll());
+        assert_eq!(daemon.metrics.errors, 1);
+        assert!(daemon.last_read_success > failing_since);
+        assert!(daemon.get_sleep_time_after_error() < Duration::from_secs(1));
+
+        // The failing source backs off by itself, the next poll skips it.
+        assert!(daemon.poll());
+        assert_eq!(daemon.metrics.errors, 1);
+    }
+
+    #[test]
+    fn core_metrics_publish_before_watched_accounts_resolve() {
+        let clock = Clock {
+            slot: 42,
+            ..Clock::default()
+        };
+        // The mint does not exist, so the snapshot never resolves it.
+        let mint = Pubkey::new_unique().to_string();
+        for publish_early in [false, true] {
+            let mut args = vec!["solana-hydrant", "--watch-mint", &mint];
+            if publish_early {
+                args.push("--publish-core-metrics-early");
+            }
+            let opts = Opts::parse_from(args);
+            let mut config = Config {
+                client: SnapshotClient::new(new_fake_rpc_client_with_sysvars(&clock)),
+            };
+            let mut daemon = Daemon::new(
+                &mut config,
+                &opts,
+                Arc::new(RpcRequestCounts::default()),
+                Arc::new(AtomicBool::new(false)),
+            );
+
+            assert!(!daemon.poll());
+            let metrics = lock_metrics(&daemon.snapshot_mutex).clone();
+            assert_eq!(metrics.ready, publish_early);
+            let slot = metrics.core.as_ref().map(|core| core.clock.slot);
+            assert_eq!(slot, if publish_early { Some(42) } else { None });
+            assert!(metrics.token_mints.is_empty());
+        }
+    }
+
+    #[test]
+    fn get_rate_limited_sleep_time_backs_off_longer() {
+        assert_eq!(
+            get_rate_limited_sleep_time(MIN_BACKOFF),
+            RATE_LIMITED_MIN_BACKOFF
+        );
+        assert_eq!(
+            get_rate_limited_sleep_time(Duration::from_secs(5)),
+            Duration::from_secs(20)
+        );
+        assert_eq!(get_rate_limited_sleep_time(MAX_BACKOFF), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn get_epoch_change_detects_transitions() {
+        assert_eq!(get_epoch_change(300, 300), EpochChange::Unchanged);
+        assert_eq!(get_epoch_change(300, 301), EpochChange::Advanced);
+        // If we miss polls around the boundary, we may skip an epoch entirely.
+        assert_eq!(get_epoch_change(300, 302), EpochChange::Advanced);
+    }
+
+    #[test]
+    fn get_epoch_change_handles_rollback() {
+        assert_eq!(get_epoch_change(301, 300), EpochChange::WentBackwards);
+        assert_eq!(get_epoch_change(1, 0), EpochChange::WentBackwards);
+    }
+
+    #[test]
+    fn get_backoff_sleep_time_stays_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let min = Duration::from_millis(200);
+        let max = Duration::from_secs(300);
+        let elapsed = Duration::from_secs(10);
+        for _ in 0..1000 {
+            let full = get_backoff_sleep_time(BackoffJitter::Full, elapsed, min, max, &mut rng);
+            assert!(full < elapsed);
+
+            let equal = get_backoff_sleep_time(BackoffJitter::Equal, elapsed, min, max, &mut rng);
+            assert!(equal >= elapsed / 2 && equal <= elapsed);
+
+            let decorrelated =
+                get_backoff_sleep_time(BackoffJitter::Decorrelated, elapsed, min, max, &mut rng);
+            assert!(decorrelated >= min && decorrelated <= elapsed * 3);
+        }
+    }
+
+    #[test]
+    fn get_backoff_sleep_time_respects_max() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let min = Duration::from_millis(200);
+        let max = Duration::from_secs(300);
+        let elapsed = Duration::from_secs(3600);
+        for jitter in [
+            BackoffJitter::Full,
+            BackoffJitter::Equal,
+            BackoffJitter::Decorrelated,
+        ] {
+            for _ in 0..100 {
+                assert!(get_backoff_sleep_time(jitter, elapsed, min, max, &mut rng) <= max);
+            }
+        }
+    }
+
+    #[test]
+    fn poll_streak_success_resets_errors() {
+        let mut streak = PollStreak::default();
+        streak.record_error();
+        streak.record_error();
+        assert_eq!(streak.consecutive_errors, 2);
+
+        streak.record_success();
+        assert_eq!(
+            streak,
+            PollStreak {
+                consecutive_successes: 1,
+                consecutive_errors: 0,
+            }
+        );
+
+        streak.record_error();
+        assert_eq!(
+            streak,
+            PollStreak {
+                consecutive_successes: 0,
+                consecutive_errors: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn time_spent_accumulates_across_iterations() {
+        let mut time_spent = TimeSpent::default();
+        for (poll_millis, sleep_millis) in [(300, 4_700), (1_200, 3_800), (50, 500)] {
+            time_spent.record_poll(Duration::from_millis(poll_millis));
+            time_spent.record_sleep(Duration::from_millis(sleep_millis));
+        }
+        assert_eq!(
+            time_spent,
+            TimeSpent {
+                polling: Duration::from_millis(1_550),
+                sleeping: Duration::from_millis(9_000),
+            }
+        );
+    }
+
+    #[test]
+    fn poll_durations_keeps_only_the_window() {
+        let mut recent_polls = PollDurations::new(3);
+        for millis in [100, 200, 300, 400, 500] {
+            recent_polls.record(Duration::from_millis(millis));
+        }
+        assert_eq!(recent_polls.as_seconds(), vec![0.3, 0.4, 0.5]);
+    }
+
+    #[test]
+    fn get_clock_drift_seconds_compares_to_local_time() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_650_000_012_500);
+        assert_eq!(get_clock_drift_seconds(1_650_000_000, now), 12.5);
+        // The cluster clock can also be ahead of ours.
+        assert_eq!(get_clock_drift_seconds(1_650_000_020, now), -7.5);
+    }
+
+    #[test]
+    fn get_slot_advance_rate_divides_by_elapsed_time() {
+        assert_eq!(
+            get_slot_advance_rate(1000, 1010, Duration::from_secs(4)),
+            2.5
+        );
+        // A slot regression, e.g. after a restart from an older snapshot, is not negative.
+        assert_eq!(
+            get_slot_advance_rate(1010, 1000, Duration::from_secs(4)),
+            0.0
+        );
+        // Two polls at the same instant do not divide by zero.
+        assert_eq!(
+            get_slot_advance_rate(1000, 1010, Duration::from_secs(0)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn get_epoch_seconds_remaining_uses_observed_slot_rate() {
+        let epoch_schedule = EpochSchedule::without_warmup();
+        // Slot 1000 of epoch 1, with 431_000 slots to go.
+        let slot = 432_000 + 1000;
+        assert_eq!(
+            get_epoch_seconds_remaining(&epoch_schedule, slot, Some(2.0)),
+            215_500.0
+        );
+        // Without a rate, or while stalled, we assume 400ms per slot.
+        assert_eq!(
+            get_epoch_seconds_remaining(&epoch_schedule, slot, None),
+            172_400.0
+        );
+        assert_eq!(
+            get_epoch_seconds_remaining(&epoch_schedule, slot, Some(0.0)),
+            172_400.0
+        );
+
+        // With warmup, the first epochs are short, epoch 1 spans slots 32 through 95.
+        let epoch_schedule = EpochSchedule::custom(432_000, 432_000, true);
+        assert_eq!(
+            get_epoch_seconds_remaining(&epoch_schedule, 40, Some(2.0)),
+            28.0
+        );
+    }
+}
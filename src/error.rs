@@ -64,6 +64,57 @@ impl AsPrettyError for MissingValidatorInfoError {
     }
 }
 
+/// A snapshot lacked an account, and retrying the snapshot is disabled.
+pub struct SnapshotRetryDisabledError {
+    /// The account that was not in the snapshot.
+    ///
+    /// For a validator identity whose validator info account we did not know
+    /// yet, this is the identity.
+    pub missing_account: Pubkey,
+}
+
+impl AsPrettyError for SnapshotRetryDisabledError {
+    fn print_pretty(&self) {
+        print_red("Snapshot retry disabled error:\n");
+        println!(
+            "The snapshot did not include account {}, and retrying the \
+            snapshot with it is disabled.",
+            self.missing_account
+        );
+    }
+}
+
+/// A line in the `--accounts-file` is not a valid pubkey.
+#[derive(Debug, Eq, PartialEq)]
+pub struct InvalidAccountsFileLineError {
+    /// Line number, starting at 1.
+    pub line_number: usize,
+
+    /// Text of the line, without comment and surrounding whitespace.
+    pub text: String,
+}
+
+impl AsPrettyError for InvalidAccountsFileLineError {
+    fn print_pretty(&self) {
+        print_red("Invalid accounts file:\n");
+        println!(
+            "Line {} is not a valid base58 pubkey: {}",
+            self.line_number, self.text
+        );
+    }
+}
+
+/// None of the `--listen` addresses could be bound, we logged the error for each of them.
+#[derive(Debug)]
+pub struct NoListenAddressError;
+
+impl AsPrettyError for NoListenAddressError {
+    fn print_pretty(&self) {
+        print_red("Http server error:");
+        println!(" None of the listen addresses could be bound.");
+    }
+}
+
 pub struct SerializationError {
     pub context: String,
     pub cause: Option<Error>,
@@ -216,6 +267,35 @@ impl AsPrettyError for serde_json::Error {
     }
 }
 
+impl AsPrettyError for reqwest::Error {
+    fn print_pretty(&self) {
+        print_red("HTTP error:");
+        println!(" {}", self);
+    }
+}
+
+impl AsPrettyError for url::ParseError {
+    fn print_pretty(&self) {
+        print_red("Invalid url:");
+        println!(" {}", self);
+    }
+}
+
+impl AsPrettyError for nix::Error {
+    fn print_pretty(&self) {
+        print_red("System error:");
+        println!(" {}", self);
+    }
+}
+
+/// Errors from the Solana SDK that are not more specific, e.g. from reading a keypair file.
+impl AsPrettyError for Box<dyn std::error::Error> {
+    fn print_pretty(&self) {
+        print_red("Error:");
+        println!(" {}", self);
+    }
+}
+
 impl AsPrettyError for PubkeyError {
     fn print_pretty(&self) {
         print_red("Solana public key error:");
@@ -358,6 +438,12 @@ impl From<PubkeyError> for Error {
     }
 }
 
+impl From<InvalidAccountsFileLineError> for Error {
+    fn from(err: InvalidAccountsFileLineError) -> Error {
+        Box::new(err)
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
         Box::new(err)
@@ -370,6 +456,12 @@ impl From<Box<bincode::ErrorKind>> for Error {
     }
 }
 
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Box::new(err)
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
         Box::new(err)
@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! A fake RPC node, to test code that talks to the RPC without a network.
+//!
+//! [`FakeSender`] implements the transport underneath [`RpcClient`], so the
+//! code under test uses the real client, only the responses are fake.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use solana_account_decoder::{UiAccount, UiAccountEncoding, UiDataSliceConfig};
+use solana_client::client_error::{ClientErrorKind, Result as ClientResult};
+use solana_client::rpc_client::{RpcClient, RpcClientConfig};
+use solana_client::rpc_request::{RpcError, RpcRequest, RpcResponseErrorData};
+use solana_client::rpc_sender::{RpcSender, RpcTransportStats};
+use solana_sdk::account::Account;
+use solana_sdk::pubkey::Pubkey;
+
+/// State of the fake RPC node, shared between the test and the sender.
+#[derive(Default)]
+pub struct FakeState {
+    /// Accounts that exist on the fake network.
+    pub accounts: HashMap<Pubkey, Account>,
+
+    /// Number of requests received, by RPC method name.
+    pub requests: HashMap<String, usize>,
+
+    /// Number of upcoming `getMultipleAccounts` requests to fail with a transient IO error.
+    pub transient_errors: usize,
+
+    /// Maximum number of accounts per `getMultipleAccounts` request, like `--rpc-max-multiple-accounts`.
+    pub max_multiple_accounts: Option<usize>,
+
+    /// Account updates to apply after the upcoming `getMultipleAccounts` requests, one per request.
+    pub updates_after_read: VecDeque<(Pubkey, Account)>,
+}
+
+pub struct FakeSender {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeSender {
+    fn get_multiple_accounts(&self, params: &Value) -> ClientResult<Value> {
+        let mut state = self.state.lock().unwrap();
+        if state.transient_errors > 0 {
+            state.transient_errors -= 1;
+            return Err(
+                io::Error::new(io::ErrorKind::ConnectionReset, "Fake connection reset.").into(),
+            );
+        }
+        let pubkeys = params[0].as_array().expect("Expected a list of pubkeys.");
+        if let Some(max) = state.max_multiple_accounts {
+            if pubkeys.len() > max {
+                return Err(RpcError::RpcResponseError {
+                    code: -32602,
+                    message: format!("Too many inputs provided; max {}", max),
+                    data: RpcResponseErrorData::Empty,
+                }
+                .into());
+            }
+        }
+        let data_slice: Option<UiDataSliceConfig> =
+            serde_json::from_value(params[1]["dataSlice"].clone()).unwrap();
+        let accounts: Vec<Option<UiAccount>> = pubkeys
+            .iter()
+            .map(|pubkey| {
+                let pubkey: Pubkey = pubkey.as_str().unwrap().parse().unwrap();
+                state.accounts.get(&pubkey).map(|account| {
+                    UiAccount::encode(
+                        &pubkey,
+                        account,
+                        UiAccountEncoding::Base64,
+                        None,
+                        data_slice,
+                    )
+                })
+            })
+            .collect();
+        if let Some((pubkey, account)) = state.updates_after_read.pop_front() {
+            state.accounts.insert(pubkey, account);
+        }
+        Ok(json!({ "context": { "slot": 1 }, "value": accounts }))
+    }
+
+    fn get_program_accounts(&self, params: &Value) -> ClientResult<Value> {
+        let state = self.state.lock().unwrap();
+        let program_id: Pubkey = params[0].as_str().unwrap().parse().unwrap();
+        let accounts: Vec<Value> = state
+            .accounts
+            .iter()
+            .filter(|(_, account)| account.owner == program_id)
+            .map(|(pubkey, account)| {
+                json!({
+                    "pubkey": pubkey.to_string(),
+                    "account": UiAccount::encode(
+                        pubkey,
+                        account,
+                        UiAccountEncoding::Base64,
+                        None,
+                        None,
+                    ),
+                })
+            })
+            .collect();
+        Ok(json!(accounts))
+    }
+}
+
+impl RpcSender for FakeSender {
+    fn send(&self, request: RpcRequest, params: Value) -> ClientResult<Value> {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .requests
+            .entry(request.to_string())
+            .or_insert(0) += 1;
+
+        match request {
+            RpcRequest::GetVersion => Ok(json!({ "solana-core": "1.9.19", "feature-set": 1 })),
+            RpcRequest::GetMultipleAccounts => self.get_multiple_accounts(&params),
+            RpcRequest::GetProgramAccounts => self.get_program_accounts(&params),
+            _ => Err(
+                ClientErrorKind::Custom(format!("Fake RPC does not support {}.", request)).into(),
+            ),
+        }
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        RpcTransportStats::default()
+    }
+}
+
+/// Return an account with the given owner and data, to put in the [`FakeState`].
+pub fn test_account(owner: Pubkey, data: Vec<u8>) -> Account {
+    Account {
+        lamports: 1,
+        data,
+        owner,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+/// Return a fake RPC node, and its state.
+pub fn new_fake_sender() -> (FakeSender, Arc<Mutex<FakeState>>) {
+    let state = Arc::new(Mutex::new(FakeState::default()));
+    let sender = FakeSender {
+        state: state.clone(),
+    };
+    (sender, state)
+}
+
+/// Return an [`RpcClient`] backed by a fake RPC node, and the state of that node.
+pub fn new_fake_rpc_client() -> (RpcClient, Arc<Mutex<FakeState>>) {
+    let (sender, state) = new_fake_sender();
+    let rpc_client = RpcClient::new_sender(sender, RpcClientConfig::default());
+    (rpc_client, state)
+}
@@ -0,0 +1,290 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Utilities for querying transaction fees.
+
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde_json::json;
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_request::RpcRequest;
+use solana_client::rpc_response::Response;
+use solana_program::clock::Slot;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::message::Message;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily};
+use crate::SnapshotClient;
+
+/// Prioritization fee paid in a recent slot, as returned by `getRecentPrioritizationFees`.
+///
+/// The version of `solana_client` that we use predates this RPC method, so we
+/// define the response type ourselves.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcPrioritizationFee {
+    pub slot: Slot,
+
+    /// Fee in micro-lamports per compute unit.
+    pub prioritization_fee: u64,
+}
+
+/// Aggregates of prioritization fees over recent slots, in micro-lamports per compute unit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrioritizationFeeStats {
+    pub min: u64,
+    pub max: u64,
+    pub median: f64,
+}
+
+/// Call `getRecentPrioritizationFees`.
+///
+/// If `accounts` is nonempty, the RPC reports only fees of transactions that
+/// lock all of these accounts.
+pub fn get_recent_prioritization_fees(
+    rpc_client: &RpcClient,
+    accounts: &[Pubkey],
+) -> std::result::Result<Vec<RpcPrioritizationFee>, Error> {
+    let addresses: Vec<String> = accounts.iter().map(|account| account.to_string()).collect();
+    let fees = rpc_client.send(
+        RpcRequest::Custom {
+            method: "getRecentPrioritizationFees",
+        },
+        json!([addresses]),
+    )?;
+    Ok(fees)
+}
+
+/// Return the base fee per signature, in lamports.
+///
+/// The RPC API for this changed across Solana versions: `getFees` is
+/// deprecated since 1.9, in favor of `getFeeForMessage`, which prices a
+/// particular message. We ask for the fee of a message without instructions
+/// and a single signer, which is exactly the fee per signature.
+///
+/// `RpcClient::get_fee_for_message` uses the commitment of the client, at
+/// which the blockhash may not exist yet, so we make the call ourselves.
+pub fn get_lamports_per_signature(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+) -> std::result::Result<u64, Error> {
+    let (blockhash, _last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(commitment)?;
+    let payer = Pubkey::default();
+    let mut message = Message::new(&[], Some(&payer));
+    message.recent_blockhash = blockhash;
+    let response: Response<Option<u64>> = rpc_client.send(
+        RpcRequest::GetFeeForMessage,
+        json!([base64::encode(message.serialize()), commitment]),
+    )?;
+    match response.value {
+        Some(fee) => Ok(fee),
+        None => Err(Box::new(ClientError::from(ClientErrorKind::Custom(
+            "Invalid blockhash".to_string(),
+        )))),
+    }
+}
+
+/// Return the metric family for the base fee.
+fn get_base_fee_families(
+    lamports_per_signature: u64,
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    vec![MetricFamily {
+        name: "solana_lamports_per_signature",
+        help: "Base transaction fee per signature, in lamports",
+        type_: "gauge",
+        metrics: vec![Metric::new(lamports_per_signature).at(produced_at)],
+    }]
+}
+
+/// Reports the base transaction fee, with `--base-fee`.
+pub struct BaseFeeSource;
+
+impl MetricSource for BaseFeeSource {
+    fn name(&self) -> &'static str {
+        "base fee"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &["solana_lamports_per_signature"]
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        _core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let lamports_per_signature =
+            get_lamports_per_signature(client.rpc_client(), client.commitment())?;
+        Ok(get_base_fee_families(
+            lamports_per_signature,
+            SystemTime::now(),
+        ))
+    }
+}
+
+/// Reports the prioritization fees over recent slots, with `--prioritization-fees`.
+pub struct PrioritizationFeeSource<'a> {
+    /// Only count fees of transactions that lock all of these accounts, from `--fee-account`.
+    pub accounts: &'a [Pubkey],
+}
+
+impl<'a> MetricSource for PrioritizationFeeSource<'a> {
+    fn name(&self) -> &'static str {
+        "prioritization fee"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &[
+            "solana_prioritization_fee_max",
+            "solana_prioritization_fee_median",
+            "solana_prioritization_fee_min",
+        ]
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let samples = get_recent_prioritization_fees(client.rpc_client(), self.accounts)?;
+        match get_prioritization_fee_stats(&samples) {
+            Some(stats) => Ok(get_prioritization_fee_families(&stats, core.produced_at)),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Return the metric families for the prioritization fee statistics.
+fn get_prioritization_fee_families(
+    fees: &PrioritizationFeeStats,
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    vec![
+        MetricFamily {
+            name: "solana_prioritization_fee_min",
+            help:
+                "Minimum prioritization fee over recent slots, in micro-lamports per compute unit",
+            type_: "gauge",
+            metrics: vec![Metric::new(fees.min).at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_prioritization_fee_max",
+            help:
+                "Maximum prioritization fee over recent slots, in micro-lamports per compute unit",
+            type_: "gauge",
+            metrics: vec![Metric::new(fees.max).at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_prioritization_fee_median",
+            help: "Median prioritization fee over recent slots, in micro-lamports per compute unit",
+            type_: "gauge",
+            metrics: vec![Metric::new(fees.median).at(produced_at)],
+        },
+    ]
+}
+
+/// Compute the minimum, maximum, and median fee over the per-slot samples.
+///
+/// Returns `None` if there are no samples.
+pub fn get_prioritization_fee_stats(
+    samples: &[RpcPrioritizationFee],
+) -> Option<PrioritizationFeeStats> {
+    let mut fees: Vec<u64> = samples
+        .iter()
+        .map(|sample| sample.prioritization_fee)
+        .collect();
+    fees.sort_unstable();
+
+    let n = fees.len();
+    if n == 0 {
+        return None;
+    }
+
+    // For an even number of samples, the median is the mean of the two middle ones.
+    let median = if n % 2 == 0 {
+        (fees[n / 2 - 1] as f64 + fees[n / 2] as f64) / 2.0
+    } else {
+        fees[n / 2] as f64
+    };
+
+    Some(PrioritizationFeeStats {
+        min: fees[0],
+        max: fees[n - 1],
+        median,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn samples(fees: &[u64]) -> Vec<RpcPrioritizationFee> {
+        fees.iter()
+            .enumerate()
+            .map(|(i, fee)| RpcPrioritizationFee {
+                slot: 100 + i as Slot,
+                prioritization_fee: *fee,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn base_fee_families_report_lamports_per_signature() {
+        let produced_at = SystemTime::UNIX_EPOCH;
+        let mut out: Vec<u8> = Vec::new();
+        for family in get_base_fee_families(5000, produced_at) {
+            crate::prometheus::write_metric(&mut out, &family).unwrap();
+        }
+        assert!(std::str::from_utf8(&out)
+            .unwrap()
+            .contains("\nsolana_lamports_per_signature 5000 0\n"));
+        assert_eq!(
+            BaseFeeSource.family_names(),
+            &["solana_lamports_per_signature"]
+        );
+    }
+
+    #[test]
+    fn get_prioritization_fee_stats_without_samples() {
+        assert_eq!(get_prioritization_fee_stats(&[]), None);
+    }
+
+    #[test]
+    fn get_prioritization_fee_stats_odd_number_of_samples() {
+        assert_eq!(
+            get_prioritization_fee_stats(&samples(&[500, 0, 20])),
+            Some(PrioritizationFeeStats {
+                min: 0,
+                max: 500,
+                median: 20.0,
+            }),
+        );
+    }
+
+    #[test]
+    fn get_prioritization_fee_stats_even_number_of_samples() {
+        assert_eq!(
+            get_prioritization_fee_stats(&samples(&[10, 0, 30, 1000])),
+            Some(PrioritizationFeeStats {
+                min: 0,
+                max: 1000,
+                median: 20.0,
+            }),
+        );
+    }
+
+    #[test]
+    fn deserialize_rpc_prioritization_fee() {
+        let fees: Vec<RpcPrioritizationFee> =
+            serde_json::from_str(r#"[{"slot":348125,"prioritizationFee":1000}]"#).unwrap();
+        assert_eq!(fees[0].slot, 348125);
+        assert_eq!(fees[0].prioritization_fee, 1000);
+    }
+}
@@ -0,0 +1,213 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! The gossip entry of a single validator identity, from `getClusterNodes`.
+//!
+//! A validator advertises its ports in gossip, so other nodes can send it
+//! shreds and transactions. When a node drops out of gossip, or advertises the
+//! wrong ports, it still runs fine locally, but the cluster cannot reach it.
+
+use std::time::{Duration, SystemTime};
+
+use solana_client::rpc_response::RpcContactInfo;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily};
+use crate::SnapshotClient;
+
+/// What the cluster knows about an identity through gossip.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GossipStatus {
+    /// The identity this is about.
+    pub identity: Pubkey,
+
+    /// Whether the identity appears in the cluster nodes at all.
+    ///
+    /// If not, all other fields are `None`.
+    pub in_gossip: bool,
+
+    /// Advertised gossip port, if any.
+    pub gossip_port: Option<u16>,
+
+    /// Advertised TPU port, if any.
+    pub tpu_port: Option<u16>,
+
+    /// Advertised JSON RPC port, `None` for nodes that do not serve RPC.
+    pub rpc_port: Option<u16>,
+
+    /// Shred version that the node advertises, if any.
+    pub shred_version: Option<u16>,
+}
+
+/// Look up the identity in the result of `getClusterNodes`.
+pub fn get_gossip_status(identity: Pubkey, nodes: &[RpcContactInfo]) -> GossipStatus {
+    let identity_str = identity.to_string();
+    match nodes.iter().find(|node| node.pubkey == identity_str) {
+        Some(node) => GossipStatus {
+            identity,
+            in_gossip: true,
+            gossip_port: node.gossip.map(|addr| addr.port()),
+            tpu_port: node.tpu.map(|addr| addr.port()),
+            rpc_port: node.rpc.map(|addr| addr.port()),
+            shred_version: node.shred_version,
+        },
+        None => GossipStatus {
+            identity,
+            in_gossip: false,
+            gossip_port: None,
+            tpu_port: None,
+            rpc_port: None,
+            shred_version: None,
+        },
+    }
+}
+
+/// Reports the gossip entry of `--leader-identity`, with `--gossip-status`.
+pub struct GossipSource {
+    pub identity: Pubkey,
+    pub interval: Duration,
+}
+
+impl MetricSource for GossipSource {
+    fn name(&self) -> &'static str {
+        "gossip status"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &[
+            "solana_node_gossip_port",
+            "solana_node_in_gossip",
+            "solana_node_rpc_port",
+            "solana_node_tpu_port",
+        ]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let nodes = client.rpc_client().get_cluster_nodes()?;
+        let status = get_gossip_status(self.identity, &nodes);
+        Ok(get_gossip_families(&status, core.produced_at))
+    }
+}
+
+/// Return the metric families for the gossip entry of an identity.
+fn get_gossip_families(
+    status: &GossipStatus,
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    let identity = status.identity.to_string();
+    let mut in_gossip =
+        Metric::new(status.in_gossip as u64).with_label("identity", identity.clone());
+    if let Some(shred_version) = status.shred_version {
+        in_gossip = in_gossip.with_label("shred_version", shred_version.to_string());
+    }
+    let mut families = vec![MetricFamily {
+        name: "solana_node_in_gossip",
+        help: "1 if the identity appears in the cluster nodes, 0 otherwise",
+        type_: "gauge",
+        metrics: vec![in_gossip.at(produced_at)],
+    }];
+    let ports = [
+        (
+            "solana_node_gossip_port",
+            "Gossip port that the identity advertises",
+            status.gossip_port,
+        ),
+        (
+            "solana_node_tpu_port",
+            "TPU port that the identity advertises",
+            status.tpu_port,
+        ),
+        (
+            "solana_node_rpc_port",
+            "JSON RPC port that the identity advertises",
+            status.rpc_port,
+        ),
+    ];
+    for (name, help, port) in ports {
+        if let Some(port) = port {
+            families.push(MetricFamily {
+                name,
+                help,
+                type_: "gauge",
+                metrics: vec![Metric::new(port as u64)
+                    .with_label("identity", identity.clone())
+                    .at(produced_at)],
+            });
+        }
+    }
+    families
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_node(identity: Pubkey, rpc: Option<&str>) -> RpcContactInfo {
+        RpcContactInfo {
+            pubkey: identity.to_string(),
+            gossip: Some("10.0.0.1:8001".parse().unwrap()),
+            tpu: Some("10.0.0.1:8004".parse().unwrap()),
+            rpc: rpc.map(|addr| addr.parse().unwrap()),
+            version: Some("1.9.19".to_string()),
+            feature_set: Some(1),
+            shred_version: Some(8573),
+        }
+    }
+
+    #[test]
+    fn get_gossip_status_finds_identity_among_nodes() {
+        let identity = Pubkey::new_unique();
+        let nodes = vec![
+            make_node(Pubkey::new_unique(), Some("10.0.0.2:8899")),
+            make_node(identity, None),
+        ];
+        assert_eq!(
+            get_gossip_status(identity, &nodes),
+            GossipStatus {
+                identity,
+                in_gossip: true,
+                gossip_port: Some(8001),
+                tpu_port: Some(8004),
+                rpc_port: None,
+                shred_version: Some(8573),
+            }
+        );
+
+        // A node that dropped out of gossip is absent from the list.
+        let status = get_gossip_status(identity, &nodes[..1]);
+        assert!(!status.in_gossip);
+        assert_eq!(status.gossip_port, None);
+    }
+
+    #[test]
+    fn get_gossip_families_omits_ports_that_are_not_advertised() {
+        let identity = Pubkey::new_unique();
+        let nodes = vec![make_node(identity, None)];
+        let families =
+            get_gossip_families(&get_gossip_status(identity, &nodes), SystemTime::UNIX_EPOCH);
+        let names: Vec<&str> = families.iter().map(|family| family.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "solana_node_in_gossip",
+                "solana_node_gossip_port",
+                "solana_node_tpu_port"
+            ]
+        );
+        assert_eq!(families[0].metrics[0].labels.len(), 2);
+
+        let families =
+            get_gossip_families(&get_gossip_status(identity, &[]), SystemTime::UNIX_EPOCH);
+        assert_eq!(families.len(), 1);
+    }
+}
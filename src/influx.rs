@@ -0,0 +1,123 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Utilities for formatting metrics as InfluxDB line protocol.
+//!
+//! This renders the same [`MetricFamily`] values as the Prometheus formatter.
+//! Every metric becomes one line, with the labels as tags, and the value in
+//! the field `value`.
+//!
+//! See also <https://docs.influxdata.com/influxdb/v2.0/reference/syntax/line-protocol/>.
+
+use std::io;
+use std::io::Write;
+
+use crate::prometheus::{get_unix_time, MetricFamily, MetricValue};
+use crate::token::format_fixed_point;
+
+/// Escape a measurement name, tag key, or tag value.
+///
+/// Measurement names only need commas and spaces escaped, but escaping `=`
+/// there as well is harmless.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if matches!(ch, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+pub fn write_influx<W: Write>(out: &mut W, family: &MetricFamily) -> io::Result<()> {
+    for metric in &family.metrics {
+        // Influx has no representation for NaN or infinity, omit the metric.
+        if let MetricValue::Float(v) = metric.value {
+            if !v.is_finite() {
+                continue;
+            }
+        }
+
+        write!(
+            out,
+            "{}",
+            escape(&format!("{}{}", family.name, metric.suffix))
+        )?;
+        for (key, value) in &metric.labels {
+            write!(out, ",{}={}", escape(key), escape(value))?;
+        }
+
+        // Integers need the `i` suffix, otherwise Influx stores them as floats.
+        match metric.value {
+            MetricValue::Int(v) => write!(out, " value={}i", v)?,
+            MetricValue::Float(v) => write!(out, " value={}", v)?,
+            MetricValue::Fixed { amount, decimals } => {
+                write!(out, " value={}", format_fixed_point(amount, decimals))?
+            }
+        }
+
+        if let Some(unix_time) = metric.timestamp.and_then(get_unix_time) {
+            // Timestamps in Influx are nanoseconds since epoch by default.
+            write!(out, " {}", unix_time.as_nanos())?;
+        }
+
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::str;
+    use std::time::{Duration, SystemTime};
+
+    use super::write_influx;
+    use crate::prometheus::{Metric, MetricFamily};
+
+    #[test]
+    fn write_influx_without_labels() {
+        let mut out: Vec<u8> = Vec::new();
+        write_influx(
+            &mut out,
+            &MetricFamily {
+                name: "goats_teleported_total",
+                help: "Number of goats teleported since launch.",
+                type_: "counter",
+                metrics: vec![Metric::new(144)],
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            str::from_utf8(&out[..]),
+            Ok("goats_teleported_total value=144i\n")
+        )
+    }
+
+    #[test]
+    fn write_influx_with_labels_and_timestamp() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_millis(1_650_000_000_123);
+        let mut out: Vec<u8> = Vec::new();
+        write_influx(
+            &mut out,
+            &MetricFamily {
+                name: "goat_weight_kg",
+                help: "Weight of the goat in kilograms.",
+                type_: "gauge",
+                metrics: vec![
+                    Metric::new(42.5)
+                        .with_label("goat", "Pixel, the first".to_string())
+                        .at(t),
+                    Metric::new(f64::NAN).with_label("goat", "Schrödinger".to_string()),
+                ],
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            str::from_utf8(&out[..]),
+            Ok("goat_weight_kg,goat=Pixel\\,\\ the\\ first value=42.5 1650000000123000000\n")
+        )
+    }
+}
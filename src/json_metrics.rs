@@ -0,0 +1,106 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Utilities for formatting metrics as json.
+//!
+//! This renders the same [`MetricFamily`] values as the Prometheus formatter,
+//! for dashboards that would rather not parse the Prometheus text format.
+//! Every family becomes an object with its help and type, and every metric an
+//! object with its full name, labels, value, and timestamp if it has one.
+
+use serde_json::{json, Map, Value};
+
+use crate::prometheus::{get_unix_time, MetricFamily, MetricValue};
+use crate::token::{fixed_point_to_f64, lamports_to_sol};
+
+pub fn get_family_json(family: &MetricFamily) -> Value {
+    let metrics: Vec<Value> = family
+        .metrics
+        .iter()
+        .map(|metric| {
+            let labels: Map<String, Value> = metric
+                .labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), Value::from(value.clone())))
+                .collect();
+            // Json numbers are doubles in most parsers anyway, and serde_json
+            // renders NaN and infinity as null.
+            let value = match metric.value {
+                MetricValue::Int(v) => json!(v),
+                MetricValue::Float(v) => json!(v),
+                // Amounts in SOL have 9 decimals, or fewer with --sol-decimals.
+                MetricValue::Fixed {
+                    amount,
+                    decimals: 9,
+                } => json!(lamports_to_sol(amount)),
+                MetricValue::Fixed { amount, decimals } => {
+                    json!(fixed_point_to_f64(amount, decimals))
+                }
+            };
+            let mut object = json!({
+                "name": format!("{}{}", family.name, metric.suffix),
+                "labels": labels,
+                "value": value,
+            });
+            if let Some(unix_time) = metric.timestamp.and_then(get_unix_time) {
+                object["timestamp_ms"] = json!(unix_time.as_millis() as u64);
+            }
+            object
+        })
+        .collect();
+
+    json!({
+        "name": family.name,
+        "help": family.help,
+        "type": family.type_,
+        "metrics": metrics,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use serde_json::json;
+
+    use super::get_family_json;
+    use crate::prometheus::{Metric, MetricFamily};
+    use crate::token::Lamports;
+
+    #[test]
+    fn get_family_json_includes_labels_and_timestamp() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_millis(1_650_000_000_123);
+        let family = MetricFamily {
+            name: "goat_balance_sol",
+            help: "Balance of the goat in SOL.",
+            type_: "gauge",
+            metrics: vec![
+                Metric::new(Lamports(1_500_000_000))
+                    .with_label("goat", "Pixel".to_string())
+                    .at(t),
+                Metric::new(f64::NAN),
+            ],
+        };
+        assert_eq!(
+            get_family_json(&family),
+            json!({
+                "name": "goat_balance_sol",
+                "help": "Balance of the goat in SOL.",
+                "type": "gauge",
+                "metrics": [
+                    {
+                        "name": "goat_balance_sol",
+                        "labels": {"goat": "Pixel"},
+                        "value": 1.5,
+                        "timestamp_ms": 1_650_000_000_123_u64,
+                    },
+                    {
+                        "name": "goat_balance_sol",
+                        "labels": {},
+                        "value": null,
+                    },
+                ],
+            })
+        );
+    }
+}
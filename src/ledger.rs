@@ -0,0 +1,275 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Ledger retention and ledger snapshots of the RPC node.
+//!
+//! Unlike most other metrics, these are local to the node we connect to, not
+//! cluster-wide: they tell how much history this particular node retains, and
+//! how recent the snapshots are that it can serve to other validators. Ledger
+//! snapshots are unrelated to the account snapshots in [`crate::snapshot`].
+
+use std::time::{Duration, SystemTime};
+
+use solana_client::client_error::{ClientError, ClientErrorKind, Result as ClientResult};
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_request::RpcError;
+use solana_client::rpc_response::RpcSnapshotSlotInfo;
+use solana_program::clock::Slot;
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily};
+use crate::SnapshotClient;
+
+/// JSON-RPC error code for methods that the RPC node does not support.
+const JSON_RPC_METHOD_NOT_FOUND: i64 = -32601;
+
+/// Oldest data that the RPC node retains.
+///
+/// Fields are `None` if the node does not support the corresponding method.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LedgerRetention {
+    /// Oldest confirmed block that the node can serve, from `getFirstAvailableBlock`.
+    pub first_available_block: Option<Slot>,
+
+    /// Lowest slot that the node has in its ledger, from `minimumLedgerSlot`.
+    pub minimum_ledger_slot: Option<Slot>,
+}
+
+/// Slots of the latest ledger snapshots of the RPC node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotSlots {
+    /// Slot of the highest full snapshot.
+    pub full: Slot,
+
+    /// Slot of the highest incremental snapshot, `None` if the node has none.
+    ///
+    /// Nodes older than 1.9 do not support incremental snapshots.
+    pub incremental: Option<Slot>,
+
+    /// Number of slots that the latest snapshot, full or incremental, is behind the current slot.
+    pub slot_lag: u64,
+}
+
+/// Return whether the call failed because the RPC node does not support the method.
+fn is_method_not_found_error(error: &ClientError) -> bool {
+    matches!(
+        error.kind(),
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. })
+            if *code == JSON_RPC_METHOD_NOT_FOUND
+    )
+}
+
+/// Turn an error about an unsupported method into `None`, and keep any other error.
+fn ok_if_supported(result: ClientResult<Slot>) -> std::result::Result<Option<Slot>, Error> {
+    match result {
+        Ok(slot) => Ok(Some(slot)),
+        Err(err) if is_method_not_found_error(&err) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Read the ledger retention of the RPC node.
+pub fn get_ledger_retention(rpc_client: &RpcClient) -> std::result::Result<LedgerRetention, Error> {
+    Ok(LedgerRetention {
+        first_available_block: ok_if_supported(rpc_client.get_first_available_block())?,
+        minimum_ledger_slot: ok_if_supported(rpc_client.minimum_ledger_slot())?,
+    })
+}
+
+/// Read the highest snapshot slots of the RPC node.
+///
+/// The client falls back to `getSnapshotSlot` for nodes older than 1.9, which
+/// only report a full snapshot.
+pub fn get_snapshot_slots(
+    rpc_client: &RpcClient,
+    current_slot: Slot,
+) -> std::result::Result<SnapshotSlots, Error> {
+    let info = rpc_client.get_highest_snapshot_slot()?;
+    Ok(get_snapshot_slots_from_info(&info, current_slot))
+}
+
+fn get_snapshot_slots_from_info(info: &RpcSnapshotSlotInfo, current_slot: Slot) -> SnapshotSlots {
+    // An incremental snapshot builds on a full snapshot, so it is the newer one.
+    let latest = info.incremental.unwrap_or(info.full).max(info.full);
+    SnapshotSlots {
+        full: info.full,
+        incremental: info.incremental,
+        slot_lag: current_slot.saturating_sub(latest),
+    }
+}
+
+/// Reports the ledger retention of the RPC node, with `--ledger-retention`.
+pub struct LedgerRetentionSource {
+    pub interval: Duration,
+}
+
+impl MetricSource for LedgerRetentionSource {
+    fn name(&self) -> &'static str {
+        "ledger retention"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &["solana_first_available_block", "solana_minimum_ledger_slot"]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let ledger_retention = get_ledger_retention(client.rpc_client())?;
+        Ok(get_ledger_retention_families(
+            &ledger_retention,
+            core.produced_at,
+        ))
+    }
+}
+
+/// Return the metric families for the ledger retention, nodes that do not support a method omit it.
+fn get_ledger_retention_families(
+    ledger_retention: &LedgerRetention,
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    let mut families = Vec::new();
+    if let Some(slot) = ledger_retention.first_available_block {
+        families.push(MetricFamily {
+            name: "solana_first_available_block",
+            help: "Oldest block that the RPC node can serve, this is local to the node",
+            type_: "gauge",
+            metrics: vec![Metric::new(slot).at(produced_at)],
+        });
+    }
+    if let Some(slot) = ledger_retention.minimum_ledger_slot {
+        families.push(MetricFamily {
+            name: "solana_minimum_ledger_slot",
+            help: "Lowest slot in the ledger of the RPC node, this is local to the node",
+            type_: "gauge",
+            metrics: vec![Metric::new(slot).at(produced_at)],
+        });
+    }
+    families
+}
+
+/// Reports the highest ledger snapshot slots of the RPC node, with `--snapshot-slots`.
+pub struct SnapshotSlotsSource;
+
+impl MetricSource for SnapshotSlotsSource {
+    fn name(&self) -> &'static str {
+        "snapshot slots"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &[
+            "solana_highest_full_snapshot_slot",
+            "solana_highest_incremental_snapshot_slot",
+            "solana_snapshot_slot_lag",
+        ]
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let snapshot_slots = get_snapshot_slots(client.rpc_client(), core.clock.slot)?;
+        Ok(get_snapshot_slots_families(
+            &snapshot_slots,
+            core.produced_at,
+        ))
+    }
+}
+
+/// Return the metric families for the ledger snapshot slots.
+fn get_snapshot_slots_families(
+    snapshot_slots: &SnapshotSlots,
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    let mut families = vec![MetricFamily {
+        name: "solana_highest_full_snapshot_slot",
+        help: "Slot of the highest full ledger snapshot of the RPC node (not an account snapshot)",
+        type_: "gauge",
+        metrics: vec![Metric::new(snapshot_slots.full).at(produced_at)],
+    }];
+    if let Some(incremental) = snapshot_slots.incremental {
+        families.push(MetricFamily {
+            name: "solana_highest_incremental_snapshot_slot",
+            help: "Slot of the highest incremental ledger snapshot of the RPC node (not an account snapshot)",
+            type_: "gauge",
+            metrics: vec![Metric::new(incremental).at(produced_at)],
+        });
+    }
+    families.push(MetricFamily {
+        name: "solana_snapshot_slot_lag",
+        help: "Number of slots that the latest ledger snapshot of the RPC node is behind the current slot",
+        type_: "gauge",
+        metrics: vec![Metric::new(snapshot_slots.slot_lag).at(produced_at)],
+    });
+    families
+}
+
+#[cfg(test)]
+mod test {
+    use solana_client::rpc_request::RpcResponseErrorData;
+
+    use super::*;
+
+    fn make_response_error(code: i64) -> ClientError {
+        RpcError::RpcResponseError {
+            code,
+            message: "Method not found".to_string(),
+            data: RpcResponseErrorData::Empty,
+        }
+        .into()
+    }
+
+    #[test]
+    fn ok_if_supported_omits_unsupported_methods() {
+        assert_eq!(ok_if_supported(Ok(42)).ok().unwrap(), Some(42));
+        assert_eq!(
+            ok_if_supported(Err(make_response_error(-32601)))
+                .ok()
+                .unwrap(),
+            None
+        );
+        assert!(ok_if_supported(Err(make_response_error(-32602))).is_err());
+    }
+
+    #[test]
+    fn get_snapshot_slots_from_info_uses_latest_snapshot() {
+        let info = RpcSnapshotSlotInfo {
+            full: 1000,
+            incremental: Some(1900),
+        };
+        assert_eq!(
+            get_snapshot_slots_from_info(&info, 2000),
+            SnapshotSlots {
+                full: 1000,
+                incremental: Some(1900),
+                slot_lag: 100,
+            }
+        );
+
+        // Older nodes only report a full snapshot.
+        let info = RpcSnapshotSlotInfo {
+            full: 1000,
+            incremental: None,
+        };
+        assert_eq!(get_snapshot_slots_from_info(&info, 2000).slot_lag, 1000);
+    }
+
+    #[test]
+    fn get_ledger_retention_families_omits_unsupported_methods() {
+        let ledger_retention = LedgerRetention {
+            first_available_block: None,
+            minimum_ledger_slot: Some(1000),
+        };
+        let families = get_ledger_retention_families(&ledger_retention, SystemTime::UNIX_EPOCH);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].name, "solana_minimum_ledger_slot");
+    }
+}
@@ -0,0 +1,433 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Metric sources that query the RPC node by themselves.
+//!
+//! The metrics about the watched accounts come from the snapshot, and
+//! [`crate::Metrics`] renders them. Every other feature implements
+//! [`MetricSource`], which both reads from the RPC node and renders its own
+//! metric families. The daemon registers the enabled sources in
+//! [`MetricSources`] at startup. It polls them after every successful
+//! snapshot, and reports their families along with the snapshot metrics. The
+//! clock and version of the snapshot are the first source, [`CoreSource`],
+//! and every source can relate its values to them through [`CoreState`].
+//!
+//! A failing source does not fail the core poll, and it does not make the
+//! daemon back off. Instead, every source tracks its own [`SourceStatus`], and
+//! we retry it with its own backoff, so one flaky source cannot hold back the
+//! others.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+use solana_program::clock::Clock;
+
+use crate::daemon::{get_backoff_sleep_time, BackoffJitter, MAX_BACKOFF, MIN_BACKOFF};
+use crate::error::Error;
+use crate::prometheus::{Metric, MetricFamily};
+use crate::SnapshotClient;
+
+/// The clock and version that we read in the latest snapshot.
+#[derive(Clone, Debug)]
+pub struct CoreState {
+    pub clock: Clock,
+
+    /// Version of the Solana node.
+    pub version: String,
+
+    /// Identifier of the feature set of the Solana node, if it reports one.
+    pub feature_set: Option<u32>,
+
+    /// When we read the clock, this is the timestamp of the metrics.
+    pub produced_at: SystemTime,
+}
+
+impl Default for CoreState {
+    fn default() -> CoreState {
+        CoreState {
+            clock: Clock::default(),
+            version: "0.0.0".to_owned(),
+            feature_set: None,
+            produced_at: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+pub trait MetricSource {
+    /// Name of the source, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Names of all metric families that `poll` can return.
+    ///
+    /// A source whose families are all disabled with --disable-metric is not
+    /// polled at all.
+    fn family_names(&self) -> &'static [&'static str];
+
+    /// Minimum time between two successful polls, by default every poll.
+    fn interval(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Whether the families of the last successful poll still apply, given the latest core state.
+    ///
+    /// If not, we stop reporting them until the next successful poll.
+    fn is_current(&self, _core: &CoreState) -> bool {
+        true
+    }
+
+    /// Query the RPC node, and return the metric families of this source.
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error>;
+}
+
+/// Reports the clock and version of the snapshot, without any further RPC calls.
+pub struct CoreSource;
+
+impl MetricSource for CoreSource {
+    fn name(&self) -> &'static str {
+        "core"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &[
+            "solana_current_epoch",
+            "solana_current_slot",
+            "solana_feature_set",
+            "solana_version",
+        ]
+    }
+
+    fn poll(
+        &mut self,
+        _client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        Ok(get_core_families(core))
+    }
+}
+
+/// Return the metric families for the clock and version.
+fn get_core_families(core: &CoreState) -> Vec<MetricFamily<'static>> {
+    let mut version = Metric::new(1).with_label("version", core.version.clone());
+    if let Some(feature_set) = core.feature_set {
+        version = version.with_label("feature_set", feature_set.to_string());
+    }
+    let mut families = vec![
+        MetricFamily {
+            name: "solana_current_slot",
+            help: "Current slot this validator is at",
+            type_: "gauge",
+            metrics: vec![Metric::new(core.clock.slot).at(core.produced_at)],
+        },
+        MetricFamily {
+            name: "solana_current_epoch",
+            help: "Current epoch this validator is at",
+            type_: "gauge",
+            metrics: vec![Metric::new(core.clock.epoch).at(core.produced_at)],
+        },
+        MetricFamily {
+            name: "solana_version",
+            help: "version of the Solana node",
+            type_: "gauge",
+            metrics: vec![version.at(core.produced_at)],
+        },
+    ];
+    if let Some(feature_set) = core.feature_set {
+        families.push(MetricFamily {
+            name: "solana_feature_set",
+            help: "Identifier of the feature set of the Solana node",
+            type_: "gauge",
+            metrics: vec![Metric::new(feature_set as u64).at(core.produced_at)],
+        });
+    }
+    families
+}
+
+/// When a source last succeeded, and when to retry it after it failed.
+#[derive(Debug, Default)]
+pub struct SourceStatus {
+    /// The instant at which the source last succeeded, `None` if it never did.
+    last_success: Option<Instant>,
+
+    /// The instant of the first of the failures since the last success, if any.
+    failing_since: Option<Instant>,
+
+    /// After a failure, the instant before which we should not retry.
+    retry_after: Option<Instant>,
+}
+
+impl SourceStatus {
+    /// Return whether the source should run at `now`, given its interval between successes.
+    pub fn is_due(&self, now: Instant, interval: Duration) -> bool {
+        if let Some(retry_after) = self.retry_after {
+            return now >= retry_after;
+        }
+        match self.last_success {
+            None => true,
+            Some(last_success) => now.saturating_duration_since(last_success) >= interval,
+        }
+    }
+
+    pub fn record_success(&mut self, now: Instant) {
+        self.last_success = Some(now);
+        self.failing_since = None;
+        self.retry_after = None;
+    }
+
+    /// Postpone the next attempt, for longer the longer the source has been failing.
+    pub fn record_failure<R: Rng>(&mut self, now: Instant, jitter: BackoffJitter, rng: &mut R) {
+        let failing_since = *self.failing_since.get_or_insert(now);
+        let sleep_time = get_backoff_sleep_time(
+            jitter,
+            now.saturating_duration_since(failing_since),
+            MIN_BACKOFF,
+            MAX_BACKOFF,
+            rng,
+        );
+        self.retry_after = Some(now + sleep_time);
+    }
+
+    /// The instant at which the source last succeeded, `None` if it never did.
+    pub fn last_success(&self) -> Option<Instant> {
+        self.last_success
+    }
+}
+
+/// A registered source, with the families of its last successful poll.
+struct RegisteredSource<'a> {
+    source: Box<dyn MetricSource + 'a>,
+
+    status: SourceStatus,
+
+    /// Families from the last successful poll, which we keep reporting until the next one.
+    families: Vec<MetricFamily<'static>>,
+}
+
+/// The metric sources that the daemon polls, in order of registration.
+#[derive(Default)]
+pub struct MetricSources<'a> {
+    sources: Vec<RegisteredSource<'a>>,
+}
+
+impl<'a> MetricSources<'a> {
+    pub fn register(&mut self, source: Box<dyn MetricSource + 'a>) {
+        self.sources.push(RegisteredSource {
+            source,
+            status: SourceStatus::default(),
+            families: Vec::new(),
+        });
+    }
+
+    /// Remove the sources for which `f` returns false.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&dyn MetricSource) -> bool,
+    {
+        self.sources
+            .retain(|registered| f(registered.source.as_ref()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Poll the sources whose interval has elapsed, and that are not backing off.
+    ///
+    /// A source that fails keeps its families of the last successful poll, as
+    /// long as they are current, and we return its error, with the name of the
+    /// source. We retry it with backoff, independently of the other sources,
+    /// which are still polled.
+    pub fn poll_due<R: Rng>(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+        jitter: BackoffJitter,
+        rng: &mut R,
+    ) -> Vec<(&'static str, Error)> {
+        let mut errors = Vec::new();
+        let now = Instant::now();
+        for registered in self.sources.iter_mut() {
+            if !registered.source.is_current(core) {
+                registered.families.clear();
+            }
+            if !registered.status.is_due(now, registered.source.interval()) {
+                continue;
+            }
+            match registered.source.poll(client, core) {
+                Ok(families) => {
+                    registered.families = families;
+                    registered.status.record_success(Instant::now());
+                }
+                Err(err) => {
+                    registered
+                        .status
+                        .record_failure(Instant::now(), jitter, rng);
+                    errors.push((registered.source.name(), err));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Return the families of all sources, in order of registration.
+    pub fn get_metric_families(&self) -> Vec<MetricFamily<'static>> {
+        self.sources
+            .iter()
+            .flat_map(|registered| registered.families.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::fake_rpc::new_fake_rpc_client;
+    use crate::prometheus::{write_metric, MetricValue};
+
+    /// A source that reports how many times it was polled.
+    struct CountingSource {
+        name: &'static str,
+        polls: u64,
+    }
+
+    impl MetricSource for CountingSource {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn family_names(&self) -> &'static [&'static str] {
+            &["goats_polled_total"]
+        }
+
+        fn poll(
+            &mut self,
+            _client: &SnapshotClient,
+            _core: &CoreState,
+        ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+            self.polls += 1;
+            Ok(vec![MetricFamily {
+                name: "goats_polled_total",
+                help: "Number of times the goat source was polled.",
+                type_: "counter",
+                metrics: vec![Metric::new(self.polls).with_label("source", self.name.to_string())],
+            }])
+        }
+    }
+
+    #[test]
+    fn metric_sources_combine_families_in_order_of_registration() {
+        let (rpc_client, _fake_state) = new_fake_rpc_client();
+        let client = SnapshotClient::new(rpc_client);
+
+        let mut sources = MetricSources::default();
+        sources.register(Box::new(CountingSource {
+            name: "pixel",
+            polls: 0,
+        }));
+        sources.register(Box::new(CountingSource {
+            name: "schrodinger",
+            polls: 10,
+        }));
+        assert!(sources.get_metric_families().is_empty());
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let core = CoreState::default();
+        assert!(sources
+            .poll_due(&client, &core, BackoffJitter::Full, &mut rng)
+            .is_empty());
+        assert!(sources
+            .poll_due(&client, &core, BackoffJitter::Full, &mut rng)
+            .is_empty());
+        let families = sources.get_metric_families();
+        let values: Vec<(&str, &str)> = families
+            .iter()
+            .map(|family| (family.name, family.metrics[0].labels[0].1.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("goats_polled_total", "pixel"),
+                ("goats_polled_total", "schrodinger")
+            ]
+        );
+        assert!(matches!(families[0].metrics[0].value, MetricValue::Int(2)));
+        assert!(matches!(families[1].metrics[0].value, MetricValue::Int(12)));
+
+        sources.retain(|source| source.name() != "pixel");
+        assert_eq!(sources.len(), 1);
+    }
+
+    #[test]
+    fn source_status_backs_off_independently_of_interval() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let interval = Duration::from_secs(60);
+        let t0 = Instant::now();
+        let mut status = SourceStatus::default();
+        assert!(status.is_due(t0, interval));
+
+        status.record_success(t0);
+        assert!(!status.is_due(t0 + Duration::from_secs(59), interval));
+        assert!(status.is_due(t0 + Duration::from_secs(60), interval));
+
+        // After failures, the backoff decides when to retry, not the interval.
+        let t1 = t0 + Duration::from_secs(60);
+        status.record_failure(t1, BackoffJitter::Equal, &mut rng);
+        assert!(!status.is_due(t1, interval));
+        assert!(status.is_due(t1 + MIN_BACKOFF, interval));
+
+        // The longer the source fails, the longer we wait.
+        let t2 = t1 + Duration::from_secs(100);
+        status.record_failure(t2, BackoffJitter::Equal, &mut rng);
+        assert!(!status.is_due(t2 + Duration::from_secs(49), interval));
+        assert!(status.is_due(t2 + Duration::from_secs(100), interval));
+        assert_eq!(status.last_success(), Some(t0));
+
+        // A success resets the backoff.
+        let t3 = t2 + Duration::from_secs(100);
+        status.record_success(t3);
+        assert!(!status.is_due(t3 + Duration::from_secs(59), interval));
+        assert!(status.is_due(t3 + interval, interval));
+    }
+
+    #[test]
+    fn core_families_include_feature_set_if_known() {
+        let render = |core: &CoreState| {
+            let mut out: Vec<u8> = Vec::new();
+            for family in get_core_families(core) {
+                write_metric(&mut out, &family).unwrap();
+            }
+            String::from_utf8(out).unwrap()
+        };
+
+        let core = CoreState {
+            clock: Clock {
+                slot: 42,
+                ..Clock::default()
+            },
+            ..CoreState::default()
+        };
+        let out = render(&core);
+        assert!(out.contains("\nsolana_current_slot 42 0\n"));
+        assert!(out.contains("\nsolana_version{version=\"0.0.0\"} 1 0\n"));
+        assert!(!out.contains("solana_feature_set"));
+
+        let core = CoreState {
+            feature_set: Some(1_069_977_271),
+            ..core
+        };
+        let out = render(&core);
+        assert!(
+            out.contains("\nsolana_version{version=\"0.0.0\",feature_set=\"1069977271\"} 1 0\n")
+        );
+        assert!(out.contains("\nsolana_feature_set 1069977271 0\n"));
+    }
+}
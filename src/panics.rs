@@ -0,0 +1,34 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Logging and counting panics.
+//!
+//! A panic in one of the http server threads only ends that thread, so
+//! without a trace in the logs, scrapes that it would have served just stop.
+//! We log every panic with the name of the thread, and count them, so they
+//! show up in `hydrant_panics_total`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of panics since start, in any thread.
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// Return the number of panics since start.
+pub fn get_panic_count() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+/// Log and count every panic, before the default hook prints it.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        PANICS.fetch_add(1, Ordering::Relaxed);
+        let thread = std::thread::current();
+        log::error!(
+            "Thread '{}' panicked: {}",
+            thread.name().unwrap_or("<unnamed>"),
+            info
+        );
+        default_hook(info);
+    }));
+}
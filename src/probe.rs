@@ -0,0 +1,251 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Probe that measures how long it takes for a transaction to get confirmed.
+//!
+//! The probe sends a transfer of 0 lamports from the probe keypair to itself.
+//! It does not move any funds, but every probe does cost the transaction fee
+//! (5000 lamports per signature at the time of writing), so the probe account
+//! needs to be funded, and the probe is only enabled with `--probe-keypair`.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcSendTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::hash::Hash;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::system_instruction;
+use solana_sdk::transaction::Transaction;
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily};
+use crate::SnapshotClient;
+
+/// Time to wait between two checks of the signature status.
+const SIGNATURE_STATUS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Maximum time to wait for a probe transaction to get confirmed.
+///
+/// A blockhash expires after 150 blocks, which takes 60 to 90 seconds. A probe
+/// that did not confirm within a minute has failed for practical purposes.
+const MAX_PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Outcome of sending one probe transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeResult {
+    /// Whether the transaction got confirmed without error.
+    pub success: bool,
+
+    /// Time between sending the transaction and observing it confirmed, if it was.
+    pub confirmation_time: Option<Duration>,
+}
+
+impl ProbeResult {
+    pub fn failed() -> ProbeResult {
+        ProbeResult {
+            success: false,
+            confirmation_time: None,
+        }
+    }
+}
+
+/// Build a transaction that transfers 0 lamports from the keypair to itself.
+pub fn new_probe_transaction(keypair: &Keypair, recent_blockhash: Hash) -> Transaction {
+    let payer = keypair.pubkey();
+    let instruction = system_instruction::transfer(&payer, &payer, 0);
+    Transaction::new_signed_with_payer(&[instruction], Some(&payer), &[keypair], recent_blockhash)
+}
+
+/// Return how long to wait for a probe transaction, given `--watchdog-seconds`.
+///
+/// The probe waits in the poll loop, so it must give up well before the
+/// watchdog considers the poll hung. We leave half of the watchdog limit for
+/// the rest of the poll.
+pub fn get_probe_timeout(watchdog: Option<Duration>) -> Duration {
+    match watchdog {
+        Some(watchdog) => MAX_PROBE_TIMEOUT.min(watchdog / 2),
+        None => MAX_PROBE_TIMEOUT,
+    }
+}
+
+/// Send a probe transaction, and wait until it is confirmed, fails, expires, or times out.
+///
+/// This has side effects, so it must not be called inside a snapshot, which
+/// may be retried.
+pub fn run_probe(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    keypair: &Keypair,
+    timeout: Duration,
+) -> std::result::Result<ProbeResult, Error> {
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(commitment)?;
+    let transaction = new_probe_transaction(keypair, recent_blockhash);
+
+    let sent_at = Instant::now();
+    let signature = rpc_client.send_transaction_with_config(
+        &transaction,
+        RpcSendTransactionConfig {
+            preflight_commitment: Some(commitment.commitment),
+            ..RpcSendTransactionConfig::default()
+        },
+    )?;
+
+    loop {
+        match rpc_client.get_signature_status_with_commitment(&signature, commitment)? {
+            Some(Ok(())) => {
+                return Ok(ProbeResult {
+                    success: true,
+                    confirmation_time: Some(sent_at.elapsed()),
+                })
+            }
+            Some(Err(err)) => {
+                log::warn!("Probe transaction {} failed: {}", signature, err);
+                return Ok(ProbeResult::failed());
+            }
+            None => {}
+        }
+
+        // Once the blockhash is no longer valid, the transaction can no longer
+        // be included, so there is no point in waiting any longer.
+        if rpc_client.get_block_height_with_commitment(commitment)? > last_valid_block_height {
+            log::warn!(
+                "Probe transaction {} expired before it was confirmed.",
+                signature
+            );
+            return Ok(ProbeResult::failed());
+        }
+        if sent_at.elapsed() > timeout {
+            log::warn!(
+                "Probe transaction {} did not get confirmed within {:?}.",
+                signature,
+                timeout
+            );
+            return Ok(ProbeResult::failed());
+        }
+
+        std::thread::sleep(SIGNATURE_STATUS_POLL_INTERVAL);
+    }
+}
+
+/// Sends a probe transaction every `--probe-interval-seconds`, with `--probe-keypair`.
+///
+/// Sources run outside of the snapshot, so we send the transaction at most
+/// once per poll, even if the snapshot is retried.
+pub struct ProbeSource {
+    pub keypair: Keypair,
+    pub interval: Duration,
+
+    /// Time to wait for the transaction, see [`get_probe_timeout`].
+    pub timeout: Duration,
+}
+
+impl MetricSource for ProbeSource {
+    fn name(&self) -> &'static str {
+        "probe"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &["solana_probe_confirmation_seconds", "solana_probe_success"]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Run the probe, a probe that fails due to an RPC error counts as a failed probe.
+    ///
+    /// Other sources keep their last values when they fail, but a probe that
+    /// we could not send did not get confirmed either, so we report that.
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let probe = match run_probe(
+            client.rpc_client(),
+            client.commitment(),
+            &self.keypair,
+            self.timeout,
+        ) {
+            Ok(probe) => probe,
+            Err(err) => {
+                log::error!("Error while sending the probe transaction.");
+                err.print_pretty();
+                ProbeResult::failed()
+            }
+        };
+        Ok(get_probe_families(&probe, core.produced_at))
+    }
+}
+
+/// Return the metric families for the outcome of a probe.
+fn get_probe_families(probe: &ProbeResult, produced_at: SystemTime) -> Vec<MetricFamily<'static>> {
+    let mut families = vec![MetricFamily {
+        name: "solana_probe_success",
+        help: "1 if the last probe transaction got confirmed, 0 otherwise",
+        type_: "gauge",
+        metrics: vec![Metric::new(probe.success as u64).at(produced_at)],
+    }];
+    if let Some(confirmation_time) = probe.confirmation_time {
+        families.push(MetricFamily {
+            name: "solana_probe_confirmation_seconds",
+            help: "Time between sending the last probe transaction and observing it confirmed",
+            type_: "gauge",
+            metrics: vec![Metric::new(confirmation_time.as_secs_f64()).at(produced_at)],
+        });
+    }
+    families
+}
+
+#[cfg(test)]
+mod test {
+    use solana_sdk::system_program;
+
+    use super::*;
+
+    #[test]
+    fn new_probe_transaction_is_signed_self_transfer() {
+        let keypair = Keypair::new();
+        let transaction = new_probe_transaction(&keypair, Hash::new_unique());
+
+        assert!(transaction.verify().is_ok());
+        assert_eq!(transaction.signatures.len(), 1);
+        assert_eq!(transaction.message.account_keys[0], keypair.pubkey());
+        assert_eq!(transaction.message.instructions.len(), 1);
+        assert_eq!(
+            transaction.message.account_keys
+                [transaction.message.instructions[0].program_id_index as usize],
+            system_program::id()
+        );
+    }
+
+    #[test]
+    fn get_probe_timeout_stays_below_the_watchdog() {
+        assert_eq!(get_probe_timeout(None), MAX_PROBE_TIMEOUT);
+        assert_eq!(
+            get_probe_timeout(Some(Duration::from_secs(300))),
+            MAX_PROBE_TIMEOUT
+        );
+        assert_eq!(
+            get_probe_timeout(Some(Duration::from_secs(30))),
+            Duration::from_secs(15)
+        );
+    }
+
+    #[test]
+    fn get_probe_families_omits_confirmation_time_of_failed_probes() {
+        let families = get_probe_families(&ProbeResult::failed(), SystemTime::UNIX_EPOCH);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].name, "solana_probe_success");
+
+        let probe = ProbeResult {
+            success: true,
+            confirmation_time: Some(Duration::from_millis(1500)),
+        };
+        let families = get_probe_families(&probe, SystemTime::UNIX_EPOCH);
+        assert_eq!(families[1].name, "solana_probe_confirmation_seconds");
+    }
+}
@@ -7,8 +7,15 @@
 
 use std::io;
 use std::io::Write;
-use std::time::SystemTime;
+use std::str::FromStr;
+use std::sync::Once;
+use std::time::{Duration, SystemTime};
 
+use serde::Serialize;
+
+use crate::token::{format_fixed_point, Lamports};
+
+#[derive(Clone)]
 pub struct MetricFamily<'a> {
     /// Name of the metric, e.g. [`goats_teleported_total`](https://crbug.com/31482).
     pub name: &'a str,
@@ -20,11 +27,18 @@ pub struct MetricFamily<'a> {
     pub metrics: Vec<Metric<'a>>,
 }
 
+#[derive(Clone)]
 pub enum MetricValue {
     /// Render the inner value as-is, as an integer.
     Int(u64),
 
     Float(f64),
+
+    /// Render `amount / 10^decimals` as a fixed-point number, without loss of precision.
+    Fixed {
+        amount: u64,
+        decimals: u8,
+    },
 }
 
 impl From<u64> for MetricValue {
@@ -39,6 +53,17 @@ impl From<f64> for MetricValue {
     }
 }
 
+/// Render an amount of lamports as SOL, without loss of precision.
+impl From<Lamports> for MetricValue {
+    fn from(v: Lamports) -> MetricValue {
+        MetricValue::Fixed {
+            amount: v.0,
+            decimals: 9,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Metric<'a> {
     /// Suffix to append to the metric name, useful for e.g. the `_bucket` suffix on histograms.
     pub suffix: &'a str,
@@ -78,6 +103,188 @@ impl<'a> Metric<'a> {
     }
 }
 
+/// A histogram of integer observations, with fixed bucket upper bounds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Histogram {
+    /// Inclusive upper bounds of the buckets, in increasing order.
+    bounds: &'static [u64],
+
+    /// Number of observations per bucket, not cumulative.
+    ///
+    /// Has one more element than `bounds`, for observations above the highest bound.
+    counts: Vec<u64>,
+
+    /// Sum of all observations.
+    sum: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: u64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// Return the `_bucket`, `_sum`, and `_count` metrics for a family of type `histogram`.
+    ///
+    /// Prometheus expects cumulative buckets, labelled with their upper bound
+    /// in `le`, and ending in a `+Inf` bucket that equals the count.
+    pub fn get_metrics(&self) -> Vec<Metric<'static>> {
+        let mut metrics = Vec::with_capacity(self.counts.len() + 2);
+        let mut cumulative = 0;
+        let bounds = self.bounds.iter().map(|bound| bound.to_string());
+        for (bound, count) in bounds.chain(Some("+Inf".to_string())).zip(&self.counts) {
+            cumulative += count;
+            let mut metric = Metric::new(cumulative).with_label("le", bound);
+            metric.suffix = "_bucket";
+            metrics.push(metric);
+        }
+
+        let mut sum = Metric::new(self.sum);
+        sum.suffix = "_sum";
+        metrics.push(sum);
+
+        let mut count = Metric::new(cumulative);
+        count.suffix = "_count";
+        metrics.push(count);
+
+        metrics
+    }
+}
+
+/// Quantiles that we report in a summary: the minimum, median, p95, and maximum.
+pub const SUMMARY_QUANTILES: [f64; 4] = [0.0, 0.5, 0.95, 1.0];
+
+/// Return the q-quantile of values sorted in increasing order, `None` if there are no values.
+///
+/// This uses the nearest-rank method, so the quantile is always one of the
+/// values, and the 0-quantile is the minimum.
+pub fn get_quantile(sorted_values: &[f64], q: f64) -> Option<f64> {
+    let rank = (q * sorted_values.len() as f64).ceil() as usize;
+    sorted_values.get(rank.saturating_sub(1)).copied()
+}
+
+/// Return the quantile, `_sum`, and `_count` metrics for a family of type `summary`.
+///
+/// Without values, there are no quantiles to report, only a zero count.
+pub fn get_summary_metrics(values: &[f64]) -> Vec<Metric<'static>> {
+    let mut sorted_values = values.to_vec();
+    sorted_values.sort_by(|a, b| a.partial_cmp(b).expect("Values must not be NaN."));
+
+    let mut metrics = Vec::with_capacity(SUMMARY_QUANTILES.len() + 2);
+    for q in SUMMARY_QUANTILES {
+        if let Some(value) = get_quantile(&sorted_values, q) {
+            metrics.push(Metric::new(value).with_label("quantile", q.to_string()));
+        }
+    }
+
+    let mut sum = Metric::new(sorted_values.iter().sum::<f64>());
+    sum.suffix = "_sum";
+    metrics.push(sum);
+
+    let mut count = Metric::new(sorted_values.len() as u64);
+    count.suffix = "_count";
+    metrics.push(count);
+
+    metrics
+}
+
+/// Format to serve the metrics in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsFormat {
+    /// The Prometheus text format.
+    Prometheus,
+
+    /// InfluxDB line protocol.
+    Influx,
+
+    /// A json array of metric families.
+    Json,
+}
+
+impl FromStr for MetricsFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<MetricsFormat, &'static str> {
+        match s {
+            "prometheus" => Ok(MetricsFormat::Prometheus),
+            "influx" => Ok(MetricsFormat::Influx),
+            "json" => Ok(MetricsFormat::Json),
+            _ => Err("Invalid metrics format, expected 'prometheus', 'influx', or 'json'."),
+        }
+    }
+}
+
+/// Options that apply to all metric families when writing them.
+pub struct WriteOptions {
+    /// Format to serve the metrics in over http.
+    pub format: MetricsFormat,
+
+    /// Whether to include the timestamps of metrics that have one.
+    ///
+    /// Without timestamps, Prometheus uses the scrape time instead.
+    pub timestamps: bool,
+
+    /// Prefix to prepend to the name of every metric family.
+    pub prefix: String,
+
+    /// Labels to add to every metric, as name-value pairs.
+    pub labels: Vec<(String, String)>,
+
+    /// Names of metric families to omit, without the prefix.
+    pub disabled_families: Vec<String>,
+
+    /// Number of decimals to round amounts of SOL to, at most 9.
+    pub sol_decimals: u8,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            format: MetricsFormat::Prometheus,
+            timestamps: true,
+            prefix: String::new(),
+            labels: Vec::new(),
+            disabled_families: Vec::new(),
+            sol_decimals: 9,
+        }
+    }
+}
+
+/// Return the time since the Unix epoch, `None` if the timestamp is before it.
+///
+/// That can only happen when the system clock is badly off. Rather than
+/// failing the entire response, we then write the metric without timestamp,
+/// and warn about it once.
+pub fn get_unix_time(timestamp: SystemTime) -> Option<Duration> {
+    static WARN_BEFORE_EPOCH: Once = Once::new();
+    match timestamp.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => Some(duration),
+        Err(..) => {
+            WARN_BEFORE_EPOCH.call_once(|| {
+                log::warn!(
+                    "Found a metric dated before UNIX_EPOCH, is the system clock correct? \
+                    Writing metrics without timestamp."
+                )
+            });
+            None
+        }
+    }
+}
+
 pub fn write_metric<W: Write>(out: &mut W, family: &MetricFamily) -> io::Result<()> {
     writeln!(out, "# HELP {} {}", family.name, family.help)?;
     writeln!(out, "# TYPE {} {}", family.name, family.type_)?;
@@ -102,13 +309,13 @@ pub fn write_metric<W: Write>(out: &mut W, family: &MetricFamily) -> io::Result<
         match metric.value {
             MetricValue::Int(v) => write!(out, " {}", v)?,
             MetricValue::Float(v) => write!(out, " {}", v)?,
+            MetricValue::Fixed { amount, decimals } => {
+                write!(out, " {}", format_fixed_point(amount, decimals))?
+            }
         }
 
-        if let Some(timestamp) = metric.timestamp {
-            let unix_time_ms = match timestamp.duration_since(SystemTime::UNIX_EPOCH) {
-                Ok(duration) => duration.as_millis(),
-                Err(..) => panic!("Found a metric dated before UNIX_EPOCH."),
-            };
+        if let Some(unix_time) = metric.timestamp.and_then(get_unix_time) {
+            let unix_time_ms = unix_time.as_millis();
             // Timestamps in Prometheus are milliseconds since epoch,
             // excluding leap seconds. (Which is what you get if your system
             // clock tracks UTC.)
@@ -126,7 +333,7 @@ pub fn write_metric<W: Write>(out: &mut W, family: &MetricFamily) -> io::Result<
 mod test {
     use std::str;
 
-    use super::{write_metric, Metric, MetricFamily};
+    use super::{get_quantile, get_summary_metrics, write_metric, Histogram, Metric, MetricFamily};
 
     #[test]
     fn write_metric_without_labels() {
@@ -215,4 +422,99 @@ mod test {
             )
         )
     }
+
+    #[test]
+    fn write_metric_omits_timestamp_before_unix_epoch() {
+        use std::time::{Duration, SystemTime};
+
+        let mut out: Vec<u8> = Vec::new();
+        let t = SystemTime::UNIX_EPOCH - Duration::from_secs(77);
+        write_metric(
+            &mut out,
+            &MetricFamily {
+                name: "goats_teleported_total",
+                help: "Number of goats teleported since launch.",
+                type_: "counter",
+                metrics: vec![Metric::new(10).at(t)],
+            },
+        )
+        .unwrap();
+
+        assert!(str::from_utf8(&out[..])
+            .unwrap()
+            .ends_with("\ngoats_teleported_total 10\n\n"));
+    }
+
+    #[test]
+    fn write_metric_histogram() {
+        let mut histogram = Histogram::new(&[1, 2, 5]);
+        for value in [1, 1, 2, 3, 7] {
+            histogram.observe(value);
+        }
+
+        let mut out: Vec<u8> = Vec::new();
+        write_metric(
+            &mut out,
+            &MetricFamily {
+                name: "goat_jumps",
+                help: "Number of jumps per goat.",
+                type_: "histogram",
+                metrics: histogram.get_metrics(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            str::from_utf8(&out[..]),
+            Ok("# HELP goat_jumps Number of jumps per goat.\n\
+                # TYPE goat_jumps histogram\n\
+                goat_jumps_bucket{le=\"1\"} 2\n\
+                goat_jumps_bucket{le=\"2\"} 3\n\
+                goat_jumps_bucket{le=\"5\"} 4\n\
+                goat_jumps_bucket{le=\"+Inf\"} 5\n\
+                goat_jumps_sum 14\n\
+                goat_jumps_count 5\n\n\
+               ")
+        )
+    }
+
+    #[test]
+    fn get_quantile_uses_nearest_rank() {
+        let values: Vec<f64> = (1..=20).map(|i| i as f64).collect();
+        assert_eq!(get_quantile(&values, 0.0), Some(1.0));
+        assert_eq!(get_quantile(&values, 0.5), Some(10.0));
+        assert_eq!(get_quantile(&values, 0.95), Some(19.0));
+        assert_eq!(get_quantile(&values, 1.0), Some(20.0));
+
+        assert_eq!(get_quantile(&[3.0], 0.5), Some(3.0));
+        assert_eq!(get_quantile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn write_metric_summary() {
+        let mut out: Vec<u8> = Vec::new();
+        write_metric(
+            &mut out,
+            &MetricFamily {
+                name: "goat_jump_seconds",
+                help: "Duration of recent goat jumps.",
+                type_: "summary",
+                metrics: get_summary_metrics(&[0.5, 2.0, 0.25, 1.25]),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            str::from_utf8(&out[..]),
+            Ok("# HELP goat_jump_seconds Duration of recent goat jumps.\n\
+                # TYPE goat_jump_seconds summary\n\
+                goat_jump_seconds{quantile=\"0\"} 0.25\n\
+                goat_jump_seconds{quantile=\"0.5\"} 0.5\n\
+                goat_jump_seconds{quantile=\"0.95\"} 2\n\
+                goat_jump_seconds{quantile=\"1\"} 2\n\
+                goat_jump_seconds_sum 4\n\
+                goat_jump_seconds_count 4\n\n\
+               ")
+        )
+    }
 }
@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Pushing the metrics to a Prometheus Pushgateway.
+//!
+//! The Pushgateway groups pushed metrics by a grouping key, which is the job
+//! label and any further labels in the url path. We push to
+//! `<base>/metrics/job/hydrant/instance/<instance>`, so every hydrant
+//! instance has its own group. We push with POST, which replaces all metrics
+//! in that group that have the same name as a pushed metric, but keeps others,
+//! so a metric that we stop reporting lingers with its last value until it is
+//! deleted from the Pushgateway. Prometheus should scrape the Pushgateway with
+//! `honor_labels: true`, to keep the job and instance labels of the group.
+//!
+//! See also <https://github.com/prometheus/pushgateway#url>.
+
+use std::io;
+use std::time::Duration;
+
+use url::Url;
+
+use crate::error::Error;
+use crate::prometheus::{MetricsFormat, WriteOptions};
+use crate::Metrics;
+
+/// Value of the `job` label in the grouping key.
+pub const PUSHGATEWAY_JOB: &str = "hydrant";
+
+/// Build the url to push the metrics of the given job and instance to.
+///
+/// The base url may contain a path prefix, e.g. when the Pushgateway is behind
+/// a reverse proxy. The job and instance are percent-encoded as path segments.
+pub fn get_push_url(base: &str, job: &str, instance: &str) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(base)?;
+    url.path_segments_mut()
+        .map_err(|()| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+        .pop_if_empty()
+        .extend(&["metrics", "job", job, "instance", instance]);
+    Ok(url)
+}
+
+/// Render the metrics as a request body for the Pushgateway.
+///
+/// The Pushgateway rejects pushes that contain timestamps, so we always omit them.
+pub fn get_push_body(metrics: &Metrics, options: &WriteOptions) -> io::Result<Vec<u8>> {
+    let options = WriteOptions {
+        format: MetricsFormat::Prometheus,
+        timestamps: false,
+        prefix: options.prefix.clone(),
+        labels: options.labels.clone(),
+        disabled_families: options.disabled_families.clone(),
+        sol_decimals: options.sol_decimals,
+    };
+    let mut out: Vec<u8> = Vec::new();
+    metrics.write_prometheus(&mut out, &options)?;
+    Ok(out)
+}
+
+/// Build the http client to push with, so that every push can reuse its connection.
+pub fn new_push_client(timeout: Duration) -> reqwest::Result<reqwest::blocking::Client> {
+    reqwest::blocking::Client::builder()
+        .timeout(timeout)
+        .build()
+}
+
+/// Push the metrics to the Pushgateway at `url`, which should come from [`get_push_url`].
+pub fn push_metrics(
+    client: &reqwest::blocking::Client,
+    url: &Url,
+    metrics: &Metrics,
+    options: &WriteOptions,
+) -> Result<(), Error> {
+    let body = get_push_body(metrics, options)?;
+    client
+        .post(url.clone())
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(body)
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_push_url_appends_grouping_key() {
+        let url = get_push_url("http://localhost:9091", "hydrant", "node-1").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:9091/metrics/job/hydrant/instance/node-1"
+        );
+
+        // A trailing slash or path prefix on the base url is preserved, without
+        // producing an empty path segment.
+        let url = get_push_url("http://proxy/pushgateway/", "hydrant", "node-1").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://proxy/pushgateway/metrics/job/hydrant/instance/node-1"
+        );
+    }
+
+    #[test]
+    fn get_push_url_escapes_instance() {
+        let url = get_push_url("http://localhost:9091", "hydrant", "a/b c").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:9091/metrics/job/hydrant/instance/a%2Fb%20c"
+        );
+        assert!(get_push_url("not a url", "hydrant", "node-1").is_err());
+    }
+
+    #[test]
+    fn get_push_body_omits_timestamps() {
+        let metrics = Metrics::default();
+        let body = get_push_body(&metrics, &WriteOptions::default()).unwrap();
+
+        let mut expected: Vec<u8> = Vec::new();
+        let options = WriteOptions {
+            timestamps: false,
+            ..WriteOptions::default()
+        };
+        metrics.write_prometheus(&mut expected, &options).unwrap();
+        assert_eq!(body, expected);
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Rent-exemption status of the accounts in a snapshot.
+
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::sysvar::rent::Rent;
+
+use crate::snapshot::AccountSize;
+use crate::token::Lamports;
+
+/// Whether an account holds enough lamports to be exempt from rent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccountRent {
+    pub address: Pubkey,
+
+    /// Minimum balance for the account to be rent-exempt, given its data length.
+    pub minimum_balance: Lamports,
+
+    pub is_exempt: bool,
+}
+
+/// Compute the rent-exemption status of every existing account.
+///
+/// Accounts that do not exist are skipped, they have no data length to
+/// compute the minimum balance for.
+pub fn get_account_rents(rent: &Rent, account_sizes: &[AccountSize]) -> Vec<AccountRent> {
+    account_sizes
+        .iter()
+        .filter_map(|size| {
+            let data_len = size.data_len?;
+            Some(AccountRent {
+                address: size.address,
+                minimum_balance: Lamports(rent.minimum_balance(data_len)),
+                is_exempt: rent.is_exempt(size.lamports, data_len),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_account_rents_uses_data_len_and_balance() {
+        // The default rent is 3480 lamports per byte-year, with a threshold of
+        // two years, and 128 bytes of overhead per account.
+        let rent = Rent::default();
+        let token_account = Pubkey::new_unique();
+        let underfunded = Pubkey::new_unique();
+        let sizes = [
+            AccountSize {
+                address: token_account,
+                data_len: Some(165),
+                lamports: 2_039_280,
+            },
+            AccountSize {
+                address: underfunded,
+                data_len: Some(0),
+                lamports: 890_879,
+            },
+            AccountSize {
+                address: Pubkey::new_unique(),
+                data_len: None,
+                lamports: 0,
+            },
+        ];
+
+        assert_eq!(
+            get_account_rents(&rent, &sizes),
+            vec![
+                AccountRent {
+                    address: token_account,
+                    minimum_balance: Lamports(2_039_280),
+                    is_exempt: true,
+                },
+                AccountRent {
+                    address: underfunded,
+                    minimum_balance: Lamports(890_880),
+                    is_exempt: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Inflation rewards of individual stake and vote accounts.
+//!
+//! Rewards for an epoch are paid out at the start of the next epoch, so after
+//! every epoch rollover, we read the rewards of the epoch that just ended.
+
+use serde_json::json;
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcEpochConfig;
+use solana_client::rpc_request::RpcRequest;
+use solana_client::rpc_response::RpcInflationReward;
+use solana_program::clock::{Epoch, Slot};
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::Error;
+use crate::token::Lamports;
+
+/// Inflation reward that an account received for an epoch.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InflationReward {
+    pub account: Pubkey,
+
+    /// The epoch that the reward is for.
+    pub epoch: Epoch,
+
+    /// The reward, zero if the account earned no reward in the epoch.
+    pub amount: Lamports,
+
+    /// Slot in which the reward was credited, `None` if there was no reward.
+    pub effective_slot: Option<Slot>,
+}
+
+/// Pair the accounts with the rewards in the response, which is in the same order.
+///
+/// The RPC node returns `null` for accounts that earned no reward, e.g.
+/// because they were not delegated, or the vote account did not vote.
+pub fn get_inflation_rewards_from_response(
+    accounts: &[Pubkey],
+    epoch: Epoch,
+    response: &[Option<RpcInflationReward>],
+) -> Vec<InflationReward> {
+    accounts
+        .iter()
+        .zip(response)
+        .map(|(account, reward)| InflationReward {
+            account: *account,
+            epoch,
+            amount: Lamports(reward.as_ref().map_or(0, |r| r.amount)),
+            effective_slot: reward.as_ref().map(|r| r.effective_slot),
+        })
+        .collect()
+}
+
+/// Read the inflation rewards of the accounts for the given epoch.
+///
+/// `RpcClient::get_inflation_reward` uses the commitment of the client, which
+/// we cannot configure for our own sender, so we make the call ourselves.
+pub fn get_inflation_rewards(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    accounts: &[Pubkey],
+    epoch: Epoch,
+) -> std::result::Result<Vec<InflationReward>, Error> {
+    let addresses: Vec<String> = accounts.iter().map(|account| account.to_string()).collect();
+    let response: Vec<Option<RpcInflationReward>> = rpc_client.send(
+        RpcRequest::GetInflationReward,
+        json!([
+            addresses,
+            RpcEpochConfig {
+                epoch: Some(epoch),
+                commitment: Some(commitment),
+            }
+        ]),
+    )?;
+    Ok(get_inflation_rewards_from_response(
+        accounts, epoch, &response,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_inflation_rewards_from_response_emits_zero_without_reward() {
+        let rewarded = Pubkey::new_unique();
+        let unrewarded = Pubkey::new_unique();
+        let response = vec![
+            Some(RpcInflationReward {
+                epoch: 300,
+                effective_slot: 129_600_123,
+                amount: 2_500_000,
+                post_balance: 1_002_500_000,
+                commission: None,
+            }),
+            None,
+        ];
+        assert_eq!(
+            get_inflation_rewards_from_response(&[rewarded, unrewarded], 300, &response),
+            vec![
+                InflationReward {
+                    account: rewarded,
+                    epoch: 300,
+                    amount: Lamports(2_500_000),
+                    effective_slot: Some(129_600_123),
+                },
+                InflationReward {
+                    account: unrewarded,
+                    epoch: 300,
+                    amount: Lamports(0),
+                    effective_slot: None,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,298 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Sending RPC requests over an http client that we configure ourselves.
+//!
+//! The `HttpSender` of solana-client 1.9 builds its own reqwest client, with
+//! only the timeout configurable. [`ReqwestSender`] speaks the same JSON-RPC,
+//! but over a client that we build from the options.
+//!
+//! Both reuse connections: a reqwest client keeps a pool of idle connections
+//! per host. By default reqwest closes a connection after it has been idle for
+//! 90 seconds though, so with a longer poll interval every poll starts with a
+//! new TCP connection and TLS handshake. With `--rpc-keepalive` we keep idle
+//! connections open indefinitely, and send TCP keepalive probes on them so
+//! that the operating system notices when the other side is gone.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use solana_client::client_error::Result as ClientResult;
+use solana_client::rpc_custom_error::{
+    NodeUnhealthyErrorData, JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY,
+};
+use solana_client::rpc_request::{RpcError, RpcRequest, RpcResponseErrorData};
+use solana_client::rpc_sender::{RpcSender, RpcTransportStats};
+
+use crate::Opts;
+
+/// Interval between TCP keepalive probes on idle connections, with `--rpc-keepalive`.
+const TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of times to retry a request that the RPC node rejected with 429 Too Many Requests.
+///
+/// This matches the `HttpSender` of solana-client.
+const MAX_TOO_MANY_REQUESTS_RETRIES: u32 = 5;
+
+/// How to configure the http client that we send RPC requests with.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpClientOptions {
+    /// Timeout for a single request, including reading the response.
+    pub timeout: Duration,
+
+    /// Keep idle connections open indefinitely, and probe them with TCP keepalive.
+    pub keepalive: bool,
+
+    /// Value of the User-Agent header.
+    pub user_agent: String,
+}
+
+impl HttpClientOptions {
+    pub fn from_opts(opts: &Opts) -> HttpClientOptions {
+        HttpClientOptions {
+            timeout: Duration::from_secs(opts.rpc_timeout_seconds as u64),
+            keepalive: opts.rpc_keepalive,
+            user_agent: opts.rpc_user_agent.clone(),
+        }
+    }
+}
+
+/// Build the http client to send RPC requests with.
+pub fn new_http_client(options: &HttpClientOptions) -> reqwest::Result<reqwest::blocking::Client> {
+    let mut builder = reqwest::blocking::Client::builder().timeout(options.timeout);
+    if options.keepalive {
+        builder = builder
+            .pool_idle_timeout(None)
+            .tcp_keepalive(TCP_KEEPALIVE_INTERVAL);
+    }
+    builder.user_agent(&options.user_agent).build()
+}
+
+#[derive(Deserialize)]
+struct RpcErrorObject {
+    code: i64,
+    message: String,
+}
+
+/// An [`RpcSender`] that sends requests over a reqwest client that we built.
+pub struct ReqwestSender {
+    client: reqwest::blocking::Client,
+    url: String,
+    request_id: AtomicU64,
+    stats: RwLock<RpcTransportStats>,
+}
+
+impl ReqwestSender {
+    pub fn new<U: ToString>(client: reqwest::blocking::Client, url: U) -> ReqwestSender {
+        ReqwestSender {
+            client,
+            url: url.to_string(),
+            request_id: AtomicU64::new(0),
+            stats: RwLock::new(RpcTransportStats::default()),
+        }
+    }
+
+    fn send_json(&self, request_json: String) -> ClientResult<Value> {
+        let mut too_many_requests_retries = MAX_TOO_MANY_REQUESTS_RETRIES;
+        loop {
+            let response = self
+                .client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(request_json.clone())
+                .send()?;
+
+            if response.status() == StatusCode::TOO_MANY_REQUESTS && too_many_requests_retries > 0 {
+                let duration = get_retry_after(&response);
+                too_many_requests_retries -= 1;
+                log::debug!(
+                    "Rate limited by the RPC node, retrying in {:?}, {} retries left.",
+                    duration,
+                    too_many_requests_retries,
+                );
+                sleep(duration);
+                self.stats.write().unwrap().rate_limited_time += duration;
+                continue;
+            }
+
+            let body = response.error_for_status()?.bytes()?;
+            return get_result(serde_json::from_slice(&body)?);
+        }
+    }
+}
+
+/// Return how long to wait before retrying, from the Retry-After header.
+fn get_retry_after(response: &reqwest::blocking::Response) -> Duration {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|&seconds| seconds < 120)
+        .map_or(Duration::from_millis(500), Duration::from_secs)
+}
+
+/// Extract the result from a JSON-RPC response, or the error that it contains.
+fn get_result(mut json: Value) -> ClientResult<Value> {
+    if !json["error"].is_object() {
+        return Ok(json["result"].take());
+    }
+
+    let error_object: RpcErrorObject = match serde_json::from_value(json["error"].clone()) {
+        Ok(error_object) => error_object,
+        Err(err) => {
+            return Err(RpcError::RpcRequestError(format!(
+                "Failed to deserialize RPC error response: {} [{}]",
+                json["error"], err
+            ))
+            .into())
+        }
+    };
+
+    // We never send transactions, so unlike the `HttpSender` we do not need
+    // to parse the data of preflight failures.
+    let data = match error_object.code {
+        JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY => {
+            match serde_json::from_value::<NodeUnhealthyErrorData>(json["error"]["data"].take()) {
+                Ok(data) => RpcResponseErrorData::NodeUnhealthy {
+                    num_slots_behind: data.num_slots_behind,
+                },
+                Err(..) => RpcResponseErrorData::Empty,
+            }
+        }
+        _ => RpcResponseErrorData::Empty,
+    };
+
+    Err(RpcError::RpcResponseError {
+        code: error_object.code,
+        message: error_object.message,
+        data,
+    }
+    .into())
+}
+
+impl RpcSender for ReqwestSender {
+    fn send(&self, request: RpcRequest, params: Value) -> ClientResult<Value> {
+        let request_id = self.request_id.fetch_add(1, Ordering::Relaxed);
+        let request_json = json!({
+            "jsonrpc": "2.0",
+            "id": request_id,
+            "method": request.to_string(),
+            "params": params,
+        })
+        .to_string();
+
+        let start = Instant::now();
+        let result = self.send_json(request_json);
+
+        let mut stats = self.stats.write().unwrap();
+        stats.request_count += 1;
+        stats.elapsed_time += start.elapsed();
+        result
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        self.stats.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use solana_client::client_error::ClientErrorKind;
+
+    use super::*;
+    use crate::snapshot::is_rate_limited;
+    use crate::DEFAULT_RPC_USER_AGENT;
+
+    #[test]
+    fn http_client_options_follow_flags() {
+        let opts = Opts::parse_from(["solana-hydrant"]);
+        assert_eq!(
+            HttpClientOptions::from_opts(&opts),
+            HttpClientOptions {
+                timeout: Duration::from_secs(30),
+                keepalive: false,
+                user_agent: DEFAULT_RPC_USER_AGENT.to_string(),
+            }
+        );
+
+        let opts = Opts::parse_from([
+            "solana-hydrant",
+            "--rpc-keepalive",
+            "--rpc-timeout-seconds",
+            "5",
+            "--rpc-user-agent",
+            "hydrant-test/1.0",
+        ]);
+        let options = HttpClientOptions::from_opts(&opts);
+        assert_eq!(
+            options,
+            HttpClientOptions {
+                timeout: Duration::from_secs(5),
+                keepalive: true,
+                user_agent: "hydrant-test/1.0".to_string(),
+            }
+        );
+        assert!(new_http_client(&options).is_ok());
+    }
+
+    #[test]
+    fn get_result_returns_result_or_error() {
+        let response = json!({"jsonrpc": "2.0", "result": 42, "id": 0});
+        assert_eq!(get_result(response).unwrap(), json!(42));
+
+        let response = json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": -32005,
+                "message": "Node is behind by 42 slots",
+                "data": {"numSlotsBehind": 42},
+            },
+            "id": 0,
+        });
+        match get_result(response).unwrap_err().kind() {
+            ClientErrorKind::RpcError(RpcError::RpcResponseError { code, data, .. }) => {
+                assert_eq!(*code, -32005);
+                assert!(matches!(
+                    data,
+                    RpcResponseErrorData::NodeUnhealthy {
+                        num_slots_behind: Some(42)
+                    }
+                ));
+            }
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn rate_limited_requests_fail_with_too_many_requests_after_retries() {
+        // A server that throttles every request, and lets us retry right away.
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        let handler = std::thread::spawn(move || {
+            for _ in 0..=MAX_TOO_MANY_REQUESTS_RETRIES {
+                let request = server.recv().unwrap();
+                let retry_after = tiny_http::Header::from_bytes("Retry-After", "0").unwrap();
+                let response = tiny_http::Response::empty(429_u16).with_header(retry_after);
+                request.respond(response).unwrap();
+            }
+        });
+
+        let options = HttpClientOptions {
+            timeout: Duration::from_secs(5),
+            keepalive: false,
+            user_agent: DEFAULT_RPC_USER_AGENT.to_string(),
+        };
+        let sender = ReqwestSender::new(new_http_client(&options).unwrap(), url);
+        let err = sender.send(RpcRequest::GetVersion, json!([])).unwrap_err();
+        assert!(is_rate_limited(&err), "{:?}", err);
+        handler.join().unwrap();
+    }
+}
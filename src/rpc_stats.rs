@@ -0,0 +1,145 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Counting the RPC requests that we make, to attribute load on shared RPC nodes.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use serde_json::Value;
+use solana_client::client_error::Result as ClientResult;
+use solana_client::rpc_client::{RpcClient, RpcClientConfig};
+use solana_client::rpc_request::RpcRequest;
+use solana_client::rpc_sender::{RpcSender, RpcTransportStats};
+
+use crate::snapshot::is_rate_limited;
+
+/// Number of RPC requests made since start.
+#[derive(Debug, Default)]
+pub struct RpcRequestCounts {
+    /// Number of requests, by RPC method name.
+    pub by_method: Mutex<BTreeMap<String, u64>>,
+
+    /// Number of requests that failed because the RPC node rate limited us.
+    ///
+    /// The sender already retries these a few times, so they only count here
+    /// when the RPC node kept rejecting the request.
+    pub rate_limited: AtomicU64,
+}
+
+impl RpcRequestCounts {
+    /// Lock the counts by method, also when a thread panicked while it held the lock.
+    ///
+    /// A panic cannot leave a count half-updated, and losing the statistics
+    /// should not take down the daemon.
+    pub fn lock_by_method(&self) -> MutexGuard<BTreeMap<String, u64>> {
+        self.by_method
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// An [`RpcSender`] that counts the requests it forwards to the inner sender.
+///
+/// Every attempt counts, including failed ones and retries, because they all
+/// put load on the RPC node.
+pub struct CountingSender<S> {
+    inner: S,
+    counts: Arc<RpcRequestCounts>,
+}
+
+impl<S: RpcSender> RpcSender for CountingSender<S> {
+    fn send(&self, request: RpcRequest, params: Value) -> ClientResult<Value> {
+        *self
+            .counts
+            .lock_by_method()
+            .entry(request.to_string())
+            .or_insert(0) += 1;
+        let result = self.inner.send(request, params);
+        if let Err(err) = &result {
+            if is_rate_limited(err) {
+                self.counts.rate_limited.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        self.inner.get_transport_stats()
+    }
+}
+
+/// Return an [`RpcClient`] that sends requests through `sender`, and the counts of those requests.
+pub fn new_counting_rpc_client<S: RpcSender + Send + Sync + 'static>(
+    sender: S,
+    config: RpcClientConfig,
+) -> (RpcClient, Arc<RpcRequestCounts>) {
+    let counts = Arc::new(RpcRequestCounts::default());
+    let sender = CountingSender {
+        inner: sender,
+        counts: counts.clone(),
+    };
+    (RpcClient::new_sender(sender, config), counts)
+}
+
+#[cfg(test)]
+mod test {
+    use solana_sdk::pubkey::Pubkey;
+
+    use super::*;
+    use crate::fake_rpc::{new_fake_sender, test_account};
+    use crate::snapshot::SnapshotClient;
+
+    #[test]
+    fn counts_requests_by_method_including_retries() {
+        let (sender, state) = new_fake_sender();
+        let (rpc_client, counts) = new_counting_rpc_client(sender, RpcClientConfig::default());
+        let mut client = SnapshotClient::new(rpc_client).with_max_transient_retries(2);
+
+        let address = Pubkey::new_unique();
+        state
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(address, test_account(Pubkey::new_unique(), Vec::new()));
+
+        // Fail the first getMultipleAccounts with a transient error, so it is
+        // retried within the same iteration.
+        state.lock().unwrap().transient_errors = 1;
+        client
+            .with_snapshot(|mut snapshot| {
+                snapshot.get_account(&address)?;
+                Ok(())
+            })
+            .ok()
+            .unwrap();
+
+        // The first iteration does not query any accounts yet, the second one
+        // fails once and is retried. The client checks the node version once.
+        assert_eq!(counts.rate_limited.load(Ordering::Relaxed), 0);
+        let counts = counts.lock_by_method();
+        assert_eq!(counts.get("getMultipleAccounts"), Some(&2));
+        assert_eq!(counts.get("getVersion"), Some(&1));
+        assert_eq!(counts.len(), 2);
+    }
+
+    #[test]
+    fn lock_by_method_survives_a_panic_while_locked() {
+        let counts = Arc::new(RpcRequestCounts::default());
+        let counts_clone = counts.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = counts_clone.by_method.lock().unwrap();
+            panic!("Panic while holding the lock.");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(counts.by_method.is_poisoned());
+
+        *counts
+            .lock_by_method()
+            .entry("getSlot".to_string())
+            .or_insert(0) += 1;
+        assert_eq!(counts.lock_by_method().get("getSlot"), Some(&1));
+    }
+}
@@ -0,0 +1,309 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Confirmation status of transactions submitted elsewhere, with `--track-signature`.
+//!
+//! `getSignatureStatuses` only searches the status cache of the RPC node,
+//! which holds the signatures of roughly the last 300 slots. A signature that
+//! is not in the cache either did not land (yet), or it aged out of the cache.
+//! We cannot tell these apart, so we report both as unknown. Once a signature
+//! is finalized, failed, or aged out, its state no longer changes, and we stop
+//! polling it.
+
+use std::time::SystemTime;
+
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::Signature;
+use solana_transaction_status::TransactionStatus;
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily};
+use crate::SnapshotClient;
+
+/// Maximum number of signatures that `getSignatureStatuses` accepts per request.
+const MAX_SIGNATURES_PER_REQUEST: usize = 256;
+
+/// What we know about a tracked signature.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignatureState {
+    /// Not in the status cache, and we never saw it there.
+    Unknown,
+
+    /// Processed or confirmed, but not yet finalized.
+    Pending(TransactionStatus),
+
+    Finalized(TransactionStatus),
+
+    /// The transaction landed, but it failed.
+    Failed(TransactionStatus),
+
+    /// We saw the signature before it was finalized, but it is no longer in the status cache.
+    AgedOut,
+}
+
+impl SignatureState {
+    /// Return the state after a poll returned `status`, given the state before it.
+    pub fn next(&self, status: Option<TransactionStatus>) -> SignatureState {
+        match status {
+            Some(status) if status.err.is_some() => SignatureState::Failed(status),
+            Some(status) if status.satisfies_commitment(CommitmentConfig::finalized()) => {
+                SignatureState::Finalized(status)
+            }
+            Some(status) => SignatureState::Pending(status),
+            None => match self {
+                SignatureState::Pending(..) => SignatureState::AgedOut,
+                _ => SignatureState::Unknown,
+            },
+        }
+    }
+
+    /// Whether the state can no longer change, so we need not poll the signature any more.
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            SignatureState::Unknown | SignatureState::Pending(..) => false,
+            SignatureState::Finalized(..) | SignatureState::Failed(..) => true,
+            SignatureState::AgedOut => true,
+        }
+    }
+
+    fn status(&self) -> Option<&TransactionStatus> {
+        match self {
+            SignatureState::Pending(status)
+            | SignatureState::Finalized(status)
+            | SignatureState::Failed(status) => Some(status),
+            SignatureState::Unknown | SignatureState::AgedOut => None,
+        }
+    }
+
+    /// Value of the `state` label.
+    fn as_str(&self) -> &'static str {
+        match self {
+            SignatureState::Unknown | SignatureState::AgedOut => "unknown",
+            SignatureState::Pending(status) => {
+                match status.satisfies_commitment(CommitmentConfig::confirmed()) {
+                    true => "confirmed",
+                    false => "processed",
+                }
+            }
+            SignatureState::Finalized(..) => "finalized",
+            SignatureState::Failed(..) => "failed",
+        }
+    }
+}
+
+/// Reports the confirmation status of the signatures from `--track-signature`.
+pub struct SignatureSource {
+    signatures: Vec<(Signature, SignatureState)>,
+}
+
+impl SignatureSource {
+    pub fn new(signatures: &[Signature]) -> SignatureSource {
+        SignatureSource {
+            signatures: signatures
+                .iter()
+                .map(|signature| (*signature, SignatureState::Unknown))
+                .collect(),
+        }
+    }
+}
+
+impl MetricSource for SignatureSource {
+    fn name(&self) -> &'static str {
+        "signature status"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &[
+            "solana_signature_confirmations",
+            "solana_signature_confirmed",
+            "solana_signature_err",
+            "solana_signature_state",
+        ]
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        _core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let mut tracked: Vec<&mut (Signature, SignatureState)> = self
+            .signatures
+            .iter_mut()
+            .filter(|(_signature, state)| !state.is_terminal())
+            .collect();
+        for chunk in tracked.chunks_mut(MAX_SIGNATURES_PER_REQUEST) {
+            let signatures: Vec<Signature> =
+                chunk.iter().map(|(signature, _)| *signature).collect();
+            let statuses = client
+                .rpc_client()
+                .get_signature_statuses(&signatures)?
+                .value;
+            for ((_signature, state), status) in chunk.iter_mut().zip(statuses) {
+                *state = state.next(status);
+            }
+        }
+        Ok(get_signature_families(&self.signatures, SystemTime::now()))
+    }
+}
+
+/// Return the metric families for the tracked signatures.
+fn get_signature_families(
+    signatures: &[(Signature, SignatureState)],
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    let mut confirmations = Vec::new();
+    let mut confirmed = Vec::new();
+    let mut err = Vec::new();
+    let mut state_metrics = Vec::new();
+    for (signature, state) in signatures {
+        let signature = signature.to_string();
+        let status = state.status();
+        // Finalized transactions no longer count confirmations.
+        if let Some(count) = status.and_then(|status| status.confirmations) {
+            confirmations.push(
+                Metric::new(count as u64)
+                    .with_label("signature", signature.clone())
+                    .at(produced_at),
+            );
+        }
+        let is_confirmed = status.map_or(false, |status| {
+            status.satisfies_commitment(CommitmentConfig::confirmed())
+        });
+        confirmed.push(
+            Metric::new(is_confirmed as u64)
+                .with_label("signature", signature.clone())
+                .at(produced_at),
+        );
+        let is_failed = matches!(state, SignatureState::Failed(..));
+        err.push(
+            Metric::new(is_failed as u64)
+                .with_label("signature", signature.clone())
+                .at(produced_at),
+        );
+        state_metrics.push(
+            Metric::new(1)
+                .with_label("signature", signature)
+                .with_label("state", state.as_str().to_string())
+                .at(produced_at),
+        );
+    }
+    vec![
+        MetricFamily {
+            name: "solana_signature_confirmations",
+            help: "Number of confirmations of a tracked transaction, absent once it is finalized",
+            type_: "gauge",
+            metrics: confirmations,
+        },
+        MetricFamily {
+            name: "solana_signature_confirmed",
+            help: "1 if a tracked transaction reached the confirmed commitment level, 0 otherwise",
+            type_: "gauge",
+            metrics: confirmed,
+        },
+        MetricFamily {
+            name: "solana_signature_err",
+            help: "1 if a tracked transaction failed, 0 otherwise",
+            type_: "gauge",
+            metrics: err,
+        },
+        MetricFamily {
+            name: "solana_signature_state",
+            help: "1 for the state of a tracked transaction, with the state as label",
+            type_: "gauge",
+            metrics: state_metrics,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use solana_sdk::transaction::TransactionError;
+    use solana_transaction_status::TransactionConfirmationStatus;
+
+    use super::*;
+    use crate::prometheus::MetricValue;
+
+    fn get_values(families: &[MetricFamily]) -> Vec<(&'static str, String, u64)> {
+        let mut values = Vec::new();
+        for family in families {
+            for metric in &family.metrics {
+                let value = match metric.value {
+                    MetricValue::Int(value) => value,
+                    _ => panic!("Expected an integer for {}.", family.name),
+                };
+                let labels: Vec<&str> = metric.labels[1..]
+                    .iter()
+                    .map(|(_key, value)| value.as_str())
+                    .collect();
+                values.push((family.name, labels.join(","), value));
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn get_signature_families_maps_transaction_status_to_metrics() {
+        let confirmed = TransactionStatus {
+            slot: 100,
+            confirmations: Some(3),
+            status: Ok(()),
+            err: None,
+            confirmation_status: Some(TransactionConfirmationStatus::Confirmed),
+        };
+        let state = SignatureState::Unknown.next(Some(confirmed.clone()));
+        assert_eq!(state, SignatureState::Pending(confirmed));
+        assert!(!state.is_terminal());
+
+        let families = get_signature_families(&[(Signature::default(), state)], SystemTime::now());
+        assert_eq!(
+            get_values(&families),
+            vec![
+                ("solana_signature_confirmations", "".to_string(), 3),
+                ("solana_signature_confirmed", "".to_string(), 1),
+                ("solana_signature_err", "".to_string(), 0),
+                ("solana_signature_state", "confirmed".to_string(), 1),
+            ]
+        );
+
+        let failed = TransactionStatus {
+            slot: 100,
+            confirmations: None,
+            status: Err(TransactionError::InsufficientFundsForFee),
+            err: Some(TransactionError::InsufficientFundsForFee),
+            confirmation_status: Some(TransactionConfirmationStatus::Finalized),
+        };
+        let state = SignatureState::Unknown.next(Some(failed));
+        assert!(state.is_terminal());
+        let families = get_signature_families(&[(Signature::default(), state)], SystemTime::now());
+        assert_eq!(
+            get_values(&families),
+            vec![
+                ("solana_signature_confirmed", "".to_string(), 1),
+                ("solana_signature_err", "".to_string(), 1),
+                ("solana_signature_state", "failed".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn signature_state_reports_aged_out_signatures_as_unknown() {
+        let processed = TransactionStatus {
+            slot: 100,
+            confirmations: Some(0),
+            status: Ok(()),
+            err: None,
+            confirmation_status: Some(TransactionConfirmationStatus::Processed),
+        };
+        // A signature that did not land yet may still land.
+        assert_eq!(SignatureState::Unknown.next(None), SignatureState::Unknown);
+        assert!(!SignatureState::Unknown.is_terminal());
+
+        let state = SignatureState::Unknown.next(Some(processed));
+        assert_eq!(state.as_str(), "processed");
+        let state = state.next(None);
+        assert_eq!(state, SignatureState::AgedOut);
+        assert!(state.is_terminal());
+        assert_eq!(state.as_str(), "unknown");
+    }
+}
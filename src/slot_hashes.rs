@@ -0,0 +1,34 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Recent slots from the `SlotHashes` sysvar.
+//!
+//! The sysvar lists the bank hashes of up to 512 recent slots on the fork
+//! that the RPC node considers current. Slots that are missing from the list
+//! were skipped on this fork, so comparing the list across nodes or over time
+//! reveals forks. For now we only report its extent.
+
+use solana_program::clock::Slot;
+use solana_sdk::slot_hashes::SlotHashes;
+
+/// Extent of the `SlotHashes` sysvar.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SlotHashesSummary {
+    /// Number of slots in the list.
+    pub count: u64,
+
+    pub oldest_slot: Slot,
+    pub newest_slot: Slot,
+}
+
+/// Summarize the slot hashes, or return `None` if the list is empty.
+///
+/// The sysvar stores the slots in descending order, but we do not rely on that.
+pub fn get_slot_hashes_summary(slot_hashes: &SlotHashes) -> Option<SlotHashesSummary> {
+    let slots = slot_hashes.iter().map(|(slot, _hash)| *slot);
+    Some(SlotHashesSummary {
+        count: slot_hashes.len() as u64,
+        oldest_slot: slots.clone().min()?,
+        newest_slot: slots.max()?,
+    })
+}
@@ -24,16 +24,32 @@
 
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use borsh::BorshDeserialize;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
 use solana_client::client_error::{ClientError, ClientErrorKind};
 use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
 use solana_client::rpc_request::RpcError;
 use solana_client::rpc_response::RpcVersionInfo;
 use solana_sdk::account::Account;
+use solana_sdk::commitment_config::CommitmentConfig;
 use solana_sdk::pubkey::Pubkey;
-use solana_sdk::sysvar::{self, clock::Clock, Sysvar};
+use solana_sdk::sysvar::{
+    self, clock::Clock, epoch_schedule::EpochSchedule, rent::Rent, slot_hashes::SlotHashes,
+    stake_history::StakeHistory, Sysvar,
+};
 
-use crate::error::{Error, MissingAccountError, MissingValidatorInfoError};
+use crate::error::{
+    Error, MissingAccountError, MissingValidatorInfoError, SerializationError,
+    SnapshotRetryDisabledError,
+};
 
 pub enum SnapshotError {
     /// We tried to access an account, but it was not present in the snapshot.
@@ -65,6 +81,33 @@ where
     }
 }
 
+/// The result of a function that ran on a snapshot, and how many snapshots it took.
+pub struct SnapshotResult<T> {
+    pub value: T,
+
+    /// The number of times we had to call the function.
+    ///
+    /// This is 1 if the first snapshot contained all accounts that the function
+    /// needed, every additional iteration is a retry.
+    pub iterations: u32,
+
+    /// The number of accounts in the snapshot that the function succeeded on.
+    pub accounts_queried: usize,
+
+    /// The number of those accounts that the function referenced.
+    ///
+    /// We only query these in the next snapshot. If this is much lower than
+    /// `accounts_queried`, we fetched accounts that are no longer needed.
+    pub accounts_referenced: usize,
+}
+
+impl<T> SnapshotResult<T> {
+    /// Return the number of times we had to retry with a new snapshot.
+    pub fn retries(&self) -> u32 {
+        self.iterations - 1
+    }
+}
+
 /// A set that preserves insertion order.
 pub struct OrderedSet<T> {
     // Invariant: the vec and set contain the same elements.
@@ -94,6 +137,32 @@ impl<T: std::hash::Hash + Copy + Eq> OrderedSet<T> {
         }
     }
 
+    /// Remove an element, preserving the order of the remaining elements.
+    ///
+    /// Returns whether the element was present.
+    pub fn remove(&mut self, element: &T) -> bool {
+        let was_present = self.elements_set.remove(element);
+        if was_present {
+            self.elements_vec.retain(|x| x != element);
+        }
+        was_present
+    }
+
+    /// Retain only the elements for which `f` returns true, preserving their order.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let elements_set = &mut self.elements_set;
+        self.elements_vec.retain(|x| {
+            let keep = f(x);
+            if !keep {
+                elements_set.remove(x);
+            }
+            keep
+        });
+    }
+
     /// Merge `other` into `self`.
     ///
     /// This preserves the order of `self`, and adds additional elements at the
@@ -105,6 +174,24 @@ impl<T: std::hash::Hash + Copy + Eq> OrderedSet<T> {
     }
 }
 
+impl<T: std::hash::Hash + Copy + Eq> Extend<T> for OrderedSet<T> {
+    /// Append the elements at the end, in order, skipping elements that are already present.
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for element in iter {
+            self.push(element);
+        }
+    }
+}
+
+impl<T: std::hash::Hash + Copy + Eq> FromIterator<T> for OrderedSet<T> {
+    /// Collect the elements in order of first occurrence, skipping duplicates.
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut result = OrderedSet::new();
+        result.extend(iter);
+        result
+    }
+}
+
 // Deref impl so we get `.len()`, `.iter()`, `.chunks()`, etc.
 // This is the same Deref impl that `Vec` has.
 impl<T> std::ops::Deref for OrderedSet<T> {
@@ -115,6 +202,45 @@ impl<T> std::ops::Deref for OrderedSet<T> {
     }
 }
 
+/// Size of the data, and balance, of an account in a snapshot.
+#[derive(Clone)]
+pub struct AccountSize {
+    pub address: Pubkey,
+
+    /// Length of the account data in bytes, `None` if the account does not exist.
+    pub data_len: Option<usize>,
+
+    /// Balance of the account in lamports, 0 if the account does not exist.
+    pub lamports: u64,
+}
+
+/// Number of bits that [`hash_account_data`] keeps.
+///
+/// Metric values are doubles, which represent integers exactly only up to
+/// 2^53, so we cannot expose a full 64-bit hash. With 52 bits, two different
+/// account datas still rarely hash equally, but it can happen, so a hash that
+/// stays the same does not prove that the data did not change.
+pub const ACCOUNT_DATA_HASH_BITS: u32 = 52;
+
+/// Hash the account data with 64-bit FNV-1a, truncated to [`ACCOUNT_DATA_HASH_BITS`] bits.
+///
+/// Unlike the hasher in the standard library, FNV-1a is fully specified, so
+/// the hash stays the same across restarts and versions of hydrant.
+pub fn hash_account_data(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash & ((1 << ACCOUNT_DATA_HASH_BITS) - 1)
+}
+
+/// Accounts by address, `None` for accounts that did not exist.
+pub type SnapshotAccounts = HashMap<Pubkey, Option<Account>>;
+
+/// Slices of account data, by address and slice.
+type AccountSlices = HashMap<(Pubkey, UiDataSliceConfig), Option<Account>>;
+
 /// A snapshot of one or more accounts.
 pub struct Snapshot<'a> {
     /// Addresses, and their values, at the time of the snapshot.
@@ -125,7 +251,7 @@ pub struct Snapshot<'a> {
     ///   account does not exist on the network, this is an error.
     /// * The key is not present. This means that we did not include it in the
     ///   snapshot, so we need to retry.
-    accounts: &'a HashMap<Pubkey, Option<Account>>,
+    accounts: &'a SnapshotAccounts,
 
     /// The accounts referenced so far, in the order of first reference.
     ///
@@ -139,6 +265,15 @@ pub struct Snapshot<'a> {
     ///   any accounts in the snapshot that we did not reference.
     accounts_referenced: &'a mut OrderedSet<Pubkey>,
 
+    /// Slices of account data at the time of the snapshot, like `accounts`.
+    ///
+    /// These are queried separately from the full accounts, so the RPC only
+    /// returns the bytes we need.
+    slices: &'a AccountSlices,
+
+    /// The slices referenced so far, like `accounts_referenced`.
+    slices_referenced: &'a mut OrderedSet<(Pubkey, UiDataSliceConfig)>,
+
     /// The wrapped client, so we can still send transactions.
     rpc_client: &'a RpcClient,
 }
@@ -148,27 +283,105 @@ impl<'a> Snapshot<'a> {
     ///
     /// Fails with `MissingAccountError` if the account does not exist.
     pub fn get_account(&mut self, address: &Pubkey) -> crate::Result<&'a Account> {
-        self.accounts_referenced.push(*address);
-        match self.accounts.get(address) {
-            Some(Some(account)) => Ok(account),
+        match self.get_account_if_exists(address)? {
+            Some(account) => Ok(account),
             // The account was included in the snapshot, but it did not exist on
             // the network at the time. This is a fatal error.
-            Some(None) => {
+            None => {
                 let error: Error = Box::new(MissingAccountError {
                     missing_account: *address,
                 });
                 Err(error.into())
             }
+        }
+    }
+
+    /// Return the account at the given address, or `None` if it does not exist.
+    pub fn get_account_if_exists(
+        &mut self,
+        address: &Pubkey,
+    ) -> crate::Result<Option<&'a Account>> {
+        self.accounts_referenced.push(*address);
+        match self.accounts.get(address) {
+            Some(account) => Ok(account.as_ref()),
             // The account was not included in the snapshot, we need to retry.
             None => Err(SnapshotError::MissingAccount),
         }
     }
 
+    /// Return an account that the user asked us to watch.
+    ///
+    /// If it does not exist, this fails with `MissingAccountError`, unless
+    /// `skip_missing` is set. Then we log a warning and return `None`, so the
+    /// other metrics of the snapshot are still reported. The account is
+    /// referenced either way, so it shows up in `solana_account_exists`.
+    pub fn get_watched_account(
+        &mut self,
+        address: &Pubkey,
+        skip_missing: bool,
+    ) -> crate::Result<Option<&'a Account>> {
+        if !skip_missing {
+            return self.get_account(address).map(Some);
+        }
+        let account = self.get_account_if_exists(address)?;
+        if account.is_none() {
+            log::warn!("Account {} does not exist, omitting it.", address);
+        }
+        Ok(account)
+    }
+
+    /// Return `length` bytes of the account data, starting at `offset`.
+    ///
+    /// Unlike [`Snapshot::get_account`], this only transfers the slice from
+    /// the RPC node. If the account data is shorter than the end of the slice,
+    /// the result is shorter than `length`.
+    ///
+    /// Fails with `MissingAccountError` if the account does not exist.
+    pub fn get_account_slice(
+        &mut self,
+        address: &Pubkey,
+        offset: usize,
+        length: usize,
+    ) -> crate::Result<&'a [u8]> {
+        let slice = UiDataSliceConfig { offset, length };
+        self.slices_referenced.push((*address, slice));
+        match self.slices.get(&(*address, slice)) {
+            Some(Some(account)) => Ok(&account.data[..]),
+            Some(None) => {
+                let error: Error = Box::new(MissingAccountError {
+                    missing_account: *address,
+                });
+                Err(error.into())
+            }
+            None => Err(SnapshotError::MissingAccount),
+        }
+    }
+
     /// Read an account and immediately bincode-deserialize it.
     pub fn get_bincode<T: Sysvar>(&mut self, address: &Pubkey) -> crate::Result<T> {
         let account = self.get_account(address)?;
-        let result = bincode::deserialize(&account.data)?;
-        Ok(result)
+        match deserialize_bincode(address, &account.data) {
+            Ok(result) => Ok(result),
+            Err(err) => {
+                let error: Error = Box::new(err);
+                Err(error.into())
+            }
+        }
+    }
+
+    /// Read an account and immediately Borsh-deserialize it.
+    ///
+    /// Trailing bytes are allowed, because programs often allocate accounts
+    /// larger than the data they currently store.
+    pub fn get_borsh<T: BorshDeserialize>(&mut self, address: &Pubkey) -> crate::Result<T> {
+        let account = self.get_account(address)?;
+        match deserialize_borsh(address, &account.data) {
+            Ok(result) => Ok(result),
+            Err(err) => {
+                let error: Error = Box::new(err);
+                Err(error.into())
+            }
+        }
     }
 
     /// Read `sysvar::clock`.
@@ -176,6 +389,72 @@ impl<'a> Snapshot<'a> {
         self.get_bincode(&sysvar::clock::id())
     }
 
+    /// Read `sysvar::rent`.
+    pub fn get_rent(&mut self) -> crate::Result<Rent> {
+        self.get_bincode(&sysvar::rent::id())
+    }
+
+    /// Read `sysvar::epoch_schedule`.
+    pub fn get_epoch_schedule(&mut self) -> crate::Result<EpochSchedule> {
+        self.get_bincode(&sysvar::epoch_schedule::id())
+    }
+
+    /// Read `sysvar::stake_history`.
+    ///
+    /// The sysvar holds up to 512 epochs, so at about 16 KiB it is much
+    /// larger than the other sysvars, only read it when needed.
+    pub fn get_stake_history(&mut self) -> crate::Result<StakeHistory> {
+        self.get_bincode(&sysvar::stake_history::id())
+    }
+
+    /// Read `sysvar::slot_hashes`.
+    ///
+    /// At about 20 KiB this sysvar is even larger than the stake history. It
+    /// counts as a single account towards the `getMultipleAccounts` limit, so
+    /// it does not make reads chunked, and therefore inconsistent, by itself.
+    /// But it does add to every response, which makes slow RPC nodes more
+    /// likely to time out, only read it when needed.
+    pub fn get_slot_hashes(&mut self) -> crate::Result<SlotHashes> {
+        self.get_bincode(&sysvar::slot_hashes::id())
+    }
+
+    /// Return the data size and balance of every account referenced so far, in order of first reference.
+    ///
+    /// The size is `None` for accounts that do not exist.
+    pub fn get_referenced_account_sizes(&self) -> Vec<AccountSize> {
+        self.accounts_referenced
+            .iter()
+            .map(|address| match self.accounts.get(address) {
+                Some(Some(account)) => AccountSize {
+                    address: *address,
+                    data_len: Some(account.data.len()),
+                    lamports: account.lamports,
+                },
+                _ => AccountSize {
+                    address: *address,
+                    data_len: None,
+                    lamports: 0,
+                },
+            })
+            .collect()
+    }
+
+    /// Return the address and data hash of every existing account referenced so far.
+    pub fn get_referenced_account_data_hashes(&self) -> Vec<(Pubkey, u64)> {
+        self.accounts_referenced
+            .iter()
+            .filter_map(|address| match self.accounts.get(address) {
+                Some(Some(account)) => Some((*address, hash_account_data(&account.data))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Return a copy of all accounts in the snapshot, also the ones not referenced so far.
+    pub fn clone_accounts(&self) -> SnapshotAccounts {
+        self.accounts.clone()
+    }
+
     /// Read validator version.
     pub fn get_version(&mut self) -> crate::Result<RpcVersionInfo> {
         self.rpc_client
@@ -195,6 +474,9 @@ pub struct SnapshotClient {
     /// going to access.
     accounts_to_query: OrderedSet<Pubkey>,
 
+    /// The initial set of account slices to query, like `accounts_to_query`.
+    slices_to_query: OrderedSet<(Pubkey, UiDataSliceConfig)>,
+
     /// Map from validator identity account address to config account address.
     validator_info_addrs: HashMap<Pubkey, Pubkey>,
 
@@ -204,24 +486,169 @@ pub struct SnapshotClient {
     /// and when we get a too-many-accounts error when requesting `n` accounts,
     /// we set this to `n - 1`, so we should quickly learn an upper bound.
     max_items_per_call: usize,
+
+    /// How many times to retry a `GetMultipleAccounts` call that failed with a
+    /// transient error, before we give up on the snapshot.
+    max_transient_retries: u32,
+
+    /// Commitment level to read at, by default the one of `rpc_client`.
+    commitment: CommitmentConfig,
+
+    /// Number of reads that needed more than one `GetMultipleAccounts` call.
+    chunked_reads: u64,
+
+    /// When we last warned about a read that needed multiple calls.
+    last_chunking_warning: Option<Instant>,
+
+    /// Minimum time between two warnings about reads that needed multiple calls.
+    chunking_warning_interval: Duration,
+
+    /// Whether to retry a snapshot that lacked an account, rather than fail.
+    snapshot_retry: bool,
+
+    /// Whether to read the accounts again after a successful snapshot, to detect changes.
+    verify_consistency: bool,
+
+    /// Number of snapshots whose accounts changed before we read them again.
+    inconsistent_snapshots: u64,
+
+    /// When we last loaded `validator_info_addrs`, `None` if we never did.
+    validator_info_loaded_at: Option<Instant>,
+
+    /// How long `validator_info_addrs` is fresh, we do not reload it before that.
+    validator_info_ttl: Duration,
+
+    /// Number of times we loaded `validator_info_addrs`.
+    validator_info_reloads: u64,
 }
 
-/// Return whether a call to `GetMultipleAccounts` failed due to the RPC account limit.
-///
-/// If this happens, the RPC operator must increase `--rpc-max-multiple-accounts`
-/// on their validator. At the time of writing, it defaults to 100.
-fn is_too_many_inputs_error(error: &ClientError) -> bool {
+/// Time to wait before retrying a call that failed with a transient error.
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Default minimum time between two warnings about reads that needed multiple calls.
+const DEFAULT_CHUNKING_WARNING_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Default time for which we do not reload the validator info config accounts.
+const DEFAULT_VALIDATOR_INFO_TTL: Duration = Duration::from_secs(600);
+
+/// Bincode-deserialize the data of the account at `address`.
+fn deserialize_bincode<T: DeserializeOwned>(
+    address: &Pubkey,
+    data: &[u8],
+) -> std::result::Result<T, SerializationError> {
+    bincode::deserialize(data).map_err(|err| SerializationError {
+        context: "Failed to bincode-deserialize account.".to_string(),
+        cause: Some(err.into()),
+        address: *address,
+    })
+}
+
+/// Borsh-deserialize the data of the account at `address`, ignoring trailing bytes.
+fn deserialize_borsh<T: BorshDeserialize>(
+    address: &Pubkey,
+    data: &[u8],
+) -> std::result::Result<T, SerializationError> {
+    T::deserialize(&mut &data[..]).map_err(|err| SerializationError {
+        context: "Failed to Borsh-deserialize account.".to_string(),
+        cause: Some(err.into()),
+        address: *address,
+    })
+}
+
+/// JSON-RPC error code for invalid parameters, which the RPC uses for the account limit.
+const JSON_RPC_INVALID_PARAMS: i64 = -32602;
+
+lazy_static! {
+    /// Matches the error code in an RPC error response that the client failed to deserialize.
+    static ref ERROR_CODE: Regex = Regex::new(r#""code"\s*:\s*(-?\d+)"#).expect("Regex is valid.");
+
+    /// Matches the message of the account limit error, with the limit if it is there.
+    static ref TOO_MANY_INPUTS: Regex =
+        Regex::new(r"Too many inputs provided(?:; max (\d+))?").expect("Regex is valid.");
+}
+
+/// Return the message of an RPC error, and its code if we know it.
+fn get_rpc_error_message(error: &ClientError) -> Option<(Option<i64>, &str)> {
     match error.kind() {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, message, .. }) => {
+            Some((Some(*code), message))
+        }
         ClientErrorKind::RpcError(RpcError::RpcRequestError(message)) => {
-            // Unfortunately, there is no way to get a structured error; all we
-            // get is a string that looks like this:
+            // If the client failed to deserialize the error, all we get is a
+            // string that looks like this:
             //
             //     Failed to deserialize RPC error response: {"code":-32602,
             //     "message":"Too many inputs provided; max 100"} [missing field `data`]
             //
-            // So we have to resort to testing for a substring, and if Solana
-            // ever changes their responses, this will break :/
-            message.contains("Too many inputs provided")
+            // So we extract the code from the string, if it is there.
+            let code = ERROR_CODE
+                .captures(message)
+                .and_then(|captures| captures[1].parse().ok());
+            Some((code, message))
+        }
+        _ => None,
+    }
+}
+
+/// Return whether a call to `GetMultipleAccounts` failed due to the RPC account limit.
+///
+/// If this happens, the RPC operator must increase `--rpc-max-multiple-accounts`
+/// on their validator. At the time of writing, it defaults to 100. Other
+/// invalid parameter errors are not about the number of accounts, so we also
+/// require the message of the account limit.
+fn is_too_many_inputs_error(error: &ClientError) -> bool {
+    match get_rpc_error_message(error) {
+        Some((Some(JSON_RPC_INVALID_PARAMS), message)) | Some((None, message)) => {
+            TOO_MANY_INPUTS.is_match(message)
+        }
+        _ => false,
+    }
+}
+
+/// Extract the account limit from a too-many-inputs error, if the message includes it.
+///
+/// The message looks like `Too many inputs provided; max 100`. Returns `None`
+/// if it does not include the limit.
+fn get_max_inputs(error: &ClientError) -> Option<usize> {
+    let (_code, message) = get_rpc_error_message(error)?;
+    TOO_MANY_INPUTS
+        .captures(message)?
+        .get(1)?
+        .as_str()
+        .parse()
+        .ok()
+}
+
+/// Return whether an RPC call failed due to a network problem that may go away by itself.
+///
+/// For these errors it makes sense to retry the call right away, for other
+/// errors (e.g. the RPC node responded with an error) it does not.
+pub fn is_transient_error(error: &ClientError) -> bool {
+    match error.kind() {
+        ClientErrorKind::Io(..) => true,
+        ClientErrorKind::Reqwest(inner) => {
+            inner.is_timeout()
+                || inner.is_connect()
+                || inner
+                    .status()
+                    .map_or(false, |status| status.is_server_error())
+        }
+        _ => false,
+    }
+}
+
+/// Return whether an RPC call failed because the RPC node rejected it with 429 Too Many Requests.
+///
+/// Shared RPC providers do this when we exceed their rate limit. Retrying
+/// right away only makes it worse, so we back off for longer than after other
+/// errors. Usually the error holds the http status, but errors that were
+/// converted to a string along the way only keep the message.
+pub fn is_rate_limited(error: &ClientError) -> bool {
+    match error.kind() {
+        ClientErrorKind::Reqwest(inner) => inner.status() == Some(StatusCode::TOO_MANY_REQUESTS),
+        ClientErrorKind::Custom(message)
+        | ClientErrorKind::RpcError(RpcError::RpcRequestError(message)) => {
+            message.contains("429 Too Many Requests")
         }
         _ => false,
     }
@@ -230,14 +657,160 @@ fn is_too_many_inputs_error(error: &ClientError) -> bool {
 impl SnapshotClient {
     pub fn new(rpc_client: RpcClient) -> SnapshotClient {
         SnapshotClient {
+            commitment: rpc_client.commitment(),
             rpc_client,
             accounts_to_query: OrderedSet::new(),
+            slices_to_query: OrderedSet::new(),
             validator_info_addrs: HashMap::new(),
             max_items_per_call: usize::MAX,
+            max_transient_retries: 0,
+            chunked_reads: 0,
+            last_chunking_warning: None,
+            chunking_warning_interval: DEFAULT_CHUNKING_WARNING_INTERVAL,
+            snapshot_retry: true,
+            verify_consistency: false,
+            inconsistent_snapshots: 0,
+            validator_info_loaded_at: None,
+            validator_info_ttl: DEFAULT_VALIDATOR_INFO_TTL,
+            validator_info_reloads: 0,
         }
     }
 
-    /// Call `GetMultipleAccounts` to get `self.accounts_to_query`.
+    /// Set how many times to retry a call that fails with a transient error.
+    ///
+    /// See also [`is_transient_error`].
+    pub fn with_max_transient_retries(mut self, max_transient_retries: u32) -> SnapshotClient {
+        self.max_transient_retries = max_transient_retries;
+        self
+    }
+
+    /// Set the commitment level to read at.
+    ///
+    /// An `RpcClient` with a custom transport always has the default
+    /// commitment, so this is the only way to use a different one there.
+    pub fn with_commitment(mut self, commitment: CommitmentConfig) -> SnapshotClient {
+        self.commitment = commitment;
+        self
+    }
+
+    /// Set whether to retry a snapshot that lacked an account that `f` accessed.
+    ///
+    /// When disabled, [`SnapshotClient::with_snapshot`] fails with
+    /// [`SnapshotRetryDisabledError`] instead. It does still learn about the
+    /// account, so a later call includes it in the snapshot.
+    pub fn with_snapshot_retry(mut self, snapshot_retry: bool) -> SnapshotClient {
+        self.snapshot_retry = snapshot_retry;
+        self
+    }
+
+    /// Set whether to read the referenced accounts again after a successful snapshot.
+    ///
+    /// When the second read differs from the snapshot, we count the snapshot
+    /// as inconsistent, see [`SnapshotClient::inconsistent_snapshots`]. This
+    /// doubles the number of `GetMultipleAccounts` calls.
+    pub fn with_verify_consistency(mut self, verify_consistency: bool) -> SnapshotClient {
+        self.verify_consistency = verify_consistency;
+        self
+    }
+
+    /// Set the minimum time between two warnings about reads that needed multiple calls.
+    pub fn with_chunking_warning_interval(mut self, interval: Duration) -> SnapshotClient {
+        self.chunking_warning_interval = interval;
+        self
+    }
+
+    /// Set for how long to reuse the validator info config accounts before we reload them.
+    ///
+    /// An identity that was not in a recent load most likely has no validator
+    /// info at all, and a reload enumerates all config accounts, so we do not
+    /// reload for every unknown identity.
+    pub fn with_validator_info_ttl(mut self, ttl: Duration) -> SnapshotClient {
+        self.validator_info_ttl = ttl;
+        self
+    }
+
+    /// Return the number of times we loaded the validator info config accounts.
+    pub fn validator_info_reloads(&self) -> u64 {
+        self.validator_info_reloads
+    }
+
+    /// Return the number of reads that needed more than one `GetMultipleAccounts` call.
+    ///
+    /// Such reads may be inconsistent, see [`SnapshotClient::with_snapshot`].
+    pub fn chunked_reads(&self) -> u64 {
+        self.chunked_reads
+    }
+
+    /// Return the number of snapshots where an account changed before we read it again.
+    ///
+    /// This is only counted with [`SnapshotClient::with_verify_consistency`].
+    /// A change does not mean that the snapshot itself was torn, but it tells
+    /// how likely a torn read is, when a read needs multiple calls.
+    pub fn inconsistent_snapshots(&self) -> u64 {
+        self.inconsistent_snapshots
+    }
+
+    /// Return the wrapped client, for RPC calls that are not part of a snapshot.
+    pub fn rpc_client(&self) -> &RpcClient {
+        &self.rpc_client
+    }
+
+    /// Return the commitment level to read at, also for calls outside of a snapshot.
+    pub fn commitment(&self) -> CommitmentConfig {
+        self.commitment
+    }
+
+    /// Read `sysvar::clock` right away, outside of a snapshot.
+    ///
+    /// Unlike a snapshot, this does not change which accounts the next snapshot queries.
+    pub fn get_clock_now(&self) -> std::result::Result<Clock, Error> {
+        let address = sysvar::clock::id();
+        let account = self
+            .rpc_client
+            .get_multiple_accounts_with_commitment(&[address], self.commitment)?
+            .value
+            .pop()
+            .flatten();
+        match account {
+            Some(account) => match deserialize_bincode(&address, &account.data) {
+                Ok(clock) => Ok(clock),
+                Err(err) => Err(Box::new(err)),
+            },
+            None => Err(Box::new(MissingAccountError {
+                missing_account: address,
+            })),
+        }
+    }
+
+    /// Query the given accounts in the next snapshot, in addition to the ones we already query.
+    ///
+    /// Normally the client learns which accounts to query from the first
+    /// attempt, and needs a retry to fetch them. When the accounts are known
+    /// upfront, preloading them avoids that extra round trip, which matters
+    /// for a one-off run. After a successful snapshot, the set of accounts to
+    /// query again follows what that snapshot referenced.
+    pub fn preload(&mut self, addresses: &[Pubkey]) {
+        self.accounts_to_query.extend(addresses.iter().copied());
+    }
+
+    /// Stop querying the account at the given address.
+    ///
+    /// Normally the set of accounts to query follows what the last successful
+    /// snapshot referenced, so an account that is no longer needed stays in the
+    /// set until the next successful snapshot. A daemon that is reconfigured at
+    /// runtime can use this to drop the account right away.
+    pub fn forget_account(&mut self, address: &Pubkey) {
+        self.accounts_to_query.remove(address);
+        self.slices_to_query.retain(|(pubkey, _)| pubkey != address);
+    }
+
+    /// Stop querying all accounts, the next snapshot will learn them from scratch.
+    pub fn clear_accounts(&mut self) {
+        self.accounts_to_query = OrderedSet::new();
+        self.slices_to_query = OrderedSet::new();
+    }
+
+    /// Call `GetMultipleAccounts` to get `pubkeys`, or only the given slice of their data.
     ///
     /// Ideally, we do a single `GetMultipleAccounts` call for the accounts we
     /// need, and then we have a consistent snapshot. But unfortunately, the
@@ -245,7 +818,7 @@ impl SnapshotClient {
     /// is quite low. This means that in somme cases, we may need to resort to
     /// doing multiple calls. This can result in torn reads, and observing an
     /// inconsistent state, but unfortunately there is no other way. If this
-    /// happens, we print a warning to stderr.
+    /// happens, we log a warning.
     ///
     /// Uses the known upper bound on the number of items that we can get per
     /// call, `max_items_per_call` (set to `usize::MAX` initially, when this is
@@ -253,19 +826,21 @@ impl SnapshotClient {
     /// maximum.
     fn get_multiple_accounts_chunked(
         &mut self,
+        pubkeys: &[Pubkey],
+        data_slice: Option<UiDataSliceConfig>,
     ) -> std::result::Result<Vec<Option<Account>>, crate::error::Error> {
         let mut result = Vec::new();
 
         // Handle the empty case first, because otherwise we try to make chunks
         // of length 0 below.
-        if self.accounts_to_query.is_empty() {
+        if pubkeys.is_empty() {
             return Ok(result);
         }
 
         'num_chunks: for num_chunks in 1.. {
             result.clear();
 
-            let items_per_chunk = self.accounts_to_query.len() / num_chunks;
+            let items_per_chunk = pubkeys.len() / num_chunks;
             assert!(
                 items_per_chunk > 0,
                 "We should be able to get at least *one* account with GetMultipleAccounts."
@@ -276,41 +851,178 @@ impl SnapshotClient {
                 continue;
             }
 
-            for chunk in self.accounts_to_query.chunks(items_per_chunk) {
-                match self.rpc_client.get_multiple_accounts(chunk) {
+            for chunk in pubkeys.chunks(items_per_chunk) {
+                match self.get_multiple_accounts_with_retry(chunk, data_slice) {
                     Ok(accounts) => {
                         result.extend(accounts);
                     }
+                    Err(err) if is_too_many_inputs_error(&err) && chunk.len() == 1 => {
+                        // We cannot make the chunks any smaller.
+                        return Err(err.into());
+                    }
                     Err(ref err) if is_too_many_inputs_error(err) => {
-                        self.max_items_per_call = chunk.len() - 1;
+                        // Use the limit that the RPC reported, but only if it
+                        // makes progress, otherwise approach it one by one.
+                        self.max_items_per_call = match get_max_inputs(err) {
+                            Some(max) if max > 0 && max < chunk.len() => max,
+                            _ => chunk.len() - 1,
+                        };
                         continue 'num_chunks;
                     }
                     Err(err) => return Err(err.into()),
                 };
             }
 
-            assert_eq!(result.len(), self.accounts_to_query.len());
+            assert_eq!(result.len(), pubkeys.len());
 
-            // Warn every time if this was not a consistent read, but only warn
-            // once per successful read.
+            // Count every read that was not consistent, but only warn once per
+            // interval, otherwise we flood the logs when this happens every poll.
             if num_chunks > 1 {
-                eprintln!(
-                    "Warning: Failed to retrieve all accounts in a single \
-                        GetMultipleAccounts call. The resulting snapshot may be \
-                        inconsistent."
-                );
-                eprintln!(
-                    "Please ask the RPC node operator to bump \
-                        --rpc-max-multiple-accounts to {}, or connect to a \
-                        different RPC node.",
-                    self.accounts_to_query.len()
-                );
+                self.chunked_reads += 1;
+                self.warn_chunked_read(pubkeys.len());
             }
 
             return Ok(result);
         }
 
-        unreachable!("Above loop fails the assertion when items_per_chunk > accounts_to_query.len");
+        unreachable!("Above loop fails the assertion when items_per_chunk > pubkeys.len");
+    }
+
+    /// Warn that a read needed multiple `GetMultipleAccounts` calls, unless we already did recently.
+    fn warn_chunked_read(&mut self, num_accounts: usize) {
+        let now = Instant::now();
+        if let Some(last_warning) = self.last_chunking_warning {
+            if now.duration_since(last_warning) < self.chunking_warning_interval {
+                return;
+            }
+        }
+        self.last_chunking_warning = Some(now);
+        log::warn!(
+            "Failed to retrieve all accounts in a single \
+            GetMultipleAccounts call. The resulting snapshot may be \
+            inconsistent. Please ask the RPC node operator to bump \
+            --rpc-max-multiple-accounts to {}, or connect to a \
+            different RPC node.",
+            num_accounts
+        );
+    }
+
+    /// Call `f`, and retry if it fails with a transient error.
+    ///
+    /// This way a single dropped connection does not fail the entire snapshot.
+    fn with_transient_retries<T, F>(&self, mut f: F) -> std::result::Result<T, ClientError>
+    where
+        F: FnMut() -> std::result::Result<T, ClientError>,
+    {
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(ref err) if is_transient_error(err) && retries < self.max_transient_retries => {
+                    retries += 1;
+                    std::thread::sleep(TRANSIENT_RETRY_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Call `GetMultipleAccounts`, and retry if it fails with a transient error.
+    fn get_multiple_accounts_with_retry(
+        &self,
+        pubkeys: &[Pubkey],
+        data_slice: Option<UiDataSliceConfig>,
+    ) -> std::result::Result<Vec<Option<Account>>, ClientError> {
+        self.with_transient_retries(|| {
+            match data_slice {
+                None => self
+                    .rpc_client
+                    .get_multiple_accounts_with_commitment(pubkeys, self.commitment),
+                Some(slice) => self.rpc_client.get_multiple_accounts_with_config(
+                    pubkeys,
+                    RpcAccountInfoConfig {
+                        encoding: Some(UiAccountEncoding::Base64),
+                        data_slice: Some(slice),
+                        commitment: Some(self.commitment),
+                    },
+                ),
+            }
+            .map(|response| response.value)
+        })
+    }
+
+    /// Reload the map from validator identity to config account, unless we loaded it recently.
+    ///
+    /// A transient error while enumerating the config accounts is retried
+    /// here, so it does not fail the entire poll.
+    fn reload_validator_info_addrs(&mut self) -> std::result::Result<(), crate::error::Error> {
+        let now = Instant::now();
+        if let Some(loaded_at) = self.validator_info_loaded_at {
+            if now.duration_since(loaded_at) < self.validator_info_ttl {
+                return Ok(());
+            }
+        }
+        let config = RpcProgramAccountsConfig {
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64Zstd),
+                commitment: Some(self.commitment),
+                ..RpcAccountInfoConfig::default()
+            },
+            ..RpcProgramAccountsConfig::default()
+        };
+        let config_accounts = self.with_transient_retries(|| {
+            self.rpc_client.get_program_accounts_with_config(
+                &solana_sdk::config::program::id(),
+                config.clone(),
+            )
+        })?;
+        self.validator_info_addrs =
+            crate::validator_info_utils::get_validator_info_mapping(&config_accounts);
+        self.validator_info_loaded_at = Some(now);
+        self.validator_info_reloads += 1;
+        Ok(())
+    }
+
+    /// Read the accounts again, and return whether any of them differs from `accounts`.
+    ///
+    /// Sysvars are excluded: the clock changes with every slot, so it would
+    /// make nearly every snapshot count as inconsistent.
+    fn has_changed_since(
+        &mut self,
+        pubkeys: &[Pubkey],
+        accounts: &SnapshotAccounts,
+    ) -> std::result::Result<bool, crate::error::Error> {
+        let pubkeys: Vec<Pubkey> = pubkeys
+            .iter()
+            .filter(|pubkey| !sysvar::is_sysvar_id(pubkey))
+            .cloned()
+            .collect();
+        let account_values = self.get_multiple_accounts_chunked(&pubkeys, None)?;
+        Ok(pubkeys
+            .iter()
+            .zip(account_values)
+            .any(|(pubkey, account)| accounts.get(pubkey) != Some(&account)))
+    }
+
+    /// Get `self.slices_to_query`, with one `GetMultipleAccounts` call per distinct slice.
+    ///
+    /// The slice applies to all accounts in a call, so accounts with different
+    /// slices cannot share a call, and the result is not necessarily
+    /// consistent with the full accounts. This is the price for the smaller
+    /// responses; use full accounts where consistency matters.
+    fn get_slices(&mut self) -> std::result::Result<AccountSlices, crate::error::Error> {
+        let mut pubkeys_by_slice: HashMap<UiDataSliceConfig, Vec<Pubkey>> = HashMap::new();
+        for (pubkey, slice) in self.slices_to_query.iter() {
+            pubkeys_by_slice.entry(*slice).or_default().push(*pubkey);
+        }
+
+        let mut result = HashMap::new();
+        for (slice, pubkeys) in pubkeys_by_slice {
+            let account_values = self.get_multiple_accounts_chunked(&pubkeys, Some(slice))?;
+            for (pubkey, account) in pubkeys.into_iter().zip(account_values) {
+                result.insert((pubkey, slice), account);
+            }
+        }
+        Ok(result)
     }
 
     /// Run the function `f`, which has access to a consistent snapshot of accounts.
@@ -326,24 +1038,28 @@ impl SnapshotClient {
     /// For the first iteration, the accounts that we load are the ones from the
     /// previous call. This means that it's better to recycle one snapshot client,
     /// than to create a new one all the time.
-    pub fn with_snapshot<T, F>(&mut self, mut f: F) -> std::result::Result<T, crate::error::Error>
+    pub fn with_snapshot<T, F>(
+        &mut self,
+        mut f: F,
+    ) -> std::result::Result<SnapshotResult<T>, crate::error::Error>
     where
         F: FnMut(Snapshot) -> crate::Result<T>,
     {
-        loop {
-            let account_values = self.get_multiple_accounts_chunked()?;
-            let accounts: HashMap<_, _> = self
-                .accounts_to_query
-                .iter()
-                .cloned()
-                .zip(account_values)
-                .collect();
+        for iterations in 1.. {
+            let pubkeys = self.accounts_to_query.to_vec();
+            let accounts_queried = pubkeys.len();
+            let account_values = self.get_multiple_accounts_chunked(&pubkeys, None)?;
+            let accounts: HashMap<_, _> = pubkeys.into_iter().zip(account_values).collect();
+            let slices = self.get_slices()?;
 
             let mut accounts_referenced = OrderedSet::new();
+            let mut slices_referenced = OrderedSet::new();
 
             let snapshot = Snapshot {
                 accounts: &accounts,
                 accounts_referenced: &mut accounts_referenced,
+                slices: &slices,
+                slices_referenced: &mut slices_referenced,
                 rpc_client: &self.rpc_client,
             };
 
@@ -355,26 +1071,53 @@ impl SnapshotClient {
                     // accounts that were once referenced, but now no longer
                     // needed, update our accounts to query to be only what `f`
                     // actually used this time.
+                    let accounts_referenced_len = accounts_referenced.len();
+                    if self.verify_consistency {
+                        // The snapshot itself is fine, a failed verification
+                        // should not discard it.
+                        match self.has_changed_since(&accounts_referenced, &accounts) {
+                            Ok(true) => {
+                                self.inconsistent_snapshots += 1;
+                                log::debug!(
+                                    "An account changed between the snapshot and reading it again."
+                                );
+                            }
+                            Ok(false) => {}
+                            Err(err) => {
+                                log::warn!("Failed to verify the consistency of the snapshot.");
+                                err.print_pretty();
+                            }
+                        }
+                    }
                     self.accounts_to_query = accounts_referenced;
-                    return Ok(result);
+                    self.slices_to_query = slices_referenced;
+                    return Ok(SnapshotResult {
+                        value: result,
+                        iterations,
+                        accounts_queried,
+                        accounts_referenced: accounts_referenced_len,
+                    });
                 }
                 Err(SnapshotError::OtherError(err)) => return Err(err),
                 Err(SnapshotError::MissingValidatorIdentity(identity_addr)) => {
                     // We tried to access the validator info config account for
                     // a validator identity that we don't know the info config
-                    // account for, so we need to reload those. After we do,
-                    // confirm that the validator identity is there, otherwise
-                    // we would get stuck in an infinite loop.
-                    self.validator_info_addrs =
-                        crate::validator_info_utils::get_validator_info_accounts(
-                            &mut self.rpc_client,
-                        )?;
+                    // account for, so we need to reload those, unless we did
+                    // so recently. After that, confirm that the validator
+                    // identity is there, otherwise we would get stuck in an
+                    // infinite loop.
+                    self.reload_validator_info_addrs()?;
 
                     if !self.validator_info_addrs.contains_key(&identity_addr) {
                         return Err(Box::new(MissingValidatorInfoError {
                             validator_identity: identity_addr,
                         }));
                     }
+                    if !self.snapshot_retry {
+                        return Err(Box::new(SnapshotRetryDisabledError {
+                            missing_account: identity_addr,
+                        }));
+                    }
                 }
                 Err(SnapshotError::MissingAccount) => {
                     // `f` tried to access an account that was not in the snapshot.
@@ -391,15 +1134,41 @@ impl SnapshotClient {
                     // that get referenced after each other will likely end up
                     // in the same chunk, and this minimizes bad effects of
                     // tearing.
+                    //
+                    // `f` stops at the first account that is missing, so
+                    // there is at most one referenced account or slice that
+                    // is not in the snapshot.
+                    let missing_account = accounts_referenced
+                        .iter()
+                        .find(|address| !accounts.contains_key(*address))
+                        .or_else(|| {
+                            slices_referenced
+                                .iter()
+                                .find(|key| !slices.contains_key(*key))
+                                .map(|(address, _slice)| address)
+                        })
+                        .copied();
                     accounts_referenced.union_with(&self.accounts_to_query);
                     self.accounts_to_query = accounts_referenced;
+                    slices_referenced.union_with(&self.slices_to_query);
+                    self.slices_to_query = slices_referenced;
+
+                    match missing_account {
+                        Some(missing_account) if !self.snapshot_retry => {
+                            return Err(Box::new(SnapshotRetryDisabledError { missing_account }));
+                        }
+                        _ => {}
+                    }
                 }
             }
         }
+
+        unreachable!("Above loop only exits by returning.");
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OutputMode {
     /// Output human-readable text to stdout.
     Text,
@@ -440,7 +1209,7 @@ pub type SnapshotConfig<'a> = Config<Snapshot<'a>>;
 pub type SnapshotClientConfig<'a> = Config<SnapshotClient>;
 
 impl<'a> SnapshotClientConfig<'a> {
-    pub fn with_snapshot<F, T>(&mut self, mut f: F) -> std::result::Result<T, Error>
+    pub fn with_snapshot<F, T>(&mut self, mut f: F) -> std::result::Result<SnapshotResult<T>, Error>
     where
         F: FnMut(&mut SnapshotConfig) -> crate::Result<T>,
     {
@@ -450,3 +1219,670 @@ impl<'a> SnapshotClientConfig<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use borsh::BorshSerialize;
+    use solana_client::rpc_request::RpcResponseErrorData;
+
+    use solana_sdk::hash::Hash;
+
+    use super::*;
+    use crate::fake_rpc::{new_fake_rpc_client, test_account};
+    use crate::slot_hashes::{get_slot_hashes_summary, SlotHashesSummary};
+
+    fn make_ordered_set(elements: &[u32]) -> OrderedSet<u32> {
+        elements.iter().copied().collect()
+    }
+
+    /// Assert that the set contains exactly `expected`, in that order.
+    fn assert_elements(set: &OrderedSet<u32>, expected: &[u32]) {
+        assert_eq!(&set[..], expected);
+        assert_eq!(set.elements_set.len(), expected.len());
+        for element in expected {
+            assert!(set.elements_set.contains(element));
+        }
+    }
+
+    #[test]
+    fn ordered_set_from_iter_dedups_in_order_of_first_occurrence() {
+        let set = make_ordered_set(&[3, 1, 3, 2, 1, 3]);
+        assert_elements(&set, &[3, 1, 2]);
+    }
+
+    #[test]
+    fn ordered_set_extend_appends_new_elements() {
+        let mut set = make_ordered_set(&[3, 1]);
+        set.extend(vec![2, 1, 4, 2]);
+        assert_elements(&set, &[3, 1, 2, 4]);
+    }
+
+    #[test]
+    fn ordered_set_remove_first_middle_last() {
+        let mut set = make_ordered_set(&[1, 2, 3, 4, 5]);
+
+        assert!(set.remove(&1));
+        assert_elements(&set, &[2, 3, 4, 5]);
+
+        assert!(set.remove(&3));
+        assert_elements(&set, &[2, 4, 5]);
+
+        assert!(set.remove(&5));
+        assert_elements(&set, &[2, 4]);
+
+        // Removing an element that is not present does nothing.
+        assert!(!set.remove(&5));
+        assert_elements(&set, &[2, 4]);
+
+        // After removing an element, we can push it again, at the end.
+        set.push(1);
+        assert_elements(&set, &[2, 4, 1]);
+    }
+
+    #[test]
+    fn ordered_set_retain() {
+        let mut set = make_ordered_set(&[5, 4, 3, 2, 1]);
+        set.retain(|x| x % 2 == 1);
+        assert_elements(&set, &[5, 3, 1]);
+    }
+
+    #[test]
+    fn is_transient_error_classifies_error_kinds() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(is_transient_error(&ClientErrorKind::Io(io_error).into()));
+
+        let rpc_error = RpcError::RpcRequestError("Too many inputs provided; max 100".into());
+        assert!(!is_transient_error(
+            &ClientErrorKind::RpcError(rpc_error).into()
+        ));
+
+        let custom_error = ClientErrorKind::Custom("Something else".into());
+        assert!(!is_transient_error(&custom_error.into()));
+    }
+
+    #[test]
+    fn is_rate_limited_detects_too_many_requests() {
+        let message = "HTTP status client error (429 Too Many Requests) for url (http://rpc/)";
+        assert!(is_rate_limited(
+            &ClientErrorKind::Custom(message.into()).into()
+        ));
+        assert!(is_rate_limited(&make_request_error(message)));
+
+        let message = "HTTP status client error (403 Forbidden) for url (http://rpc/)";
+        assert!(!is_rate_limited(
+            &ClientErrorKind::Custom(message.into()).into()
+        ));
+        let io_error = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(!is_rate_limited(&ClientErrorKind::Io(io_error).into()));
+        assert!(!is_rate_limited(&make_response_error(429, "Too many")));
+    }
+
+    fn make_request_error(message: &str) -> ClientError {
+        RpcError::RpcRequestError(message.to_string()).into()
+    }
+
+    fn make_response_error(code: i64, message: &str) -> ClientError {
+        RpcError::RpcResponseError {
+            code,
+            message: message.to_string(),
+            data: RpcResponseErrorData::Empty,
+        }
+        .into()
+    }
+
+    #[test]
+    fn is_too_many_inputs_error_structured() {
+        let err = make_response_error(-32602, "Too many inputs provided; max 100");
+        assert!(is_too_many_inputs_error(&err));
+        assert_eq!(get_max_inputs(&err), Some(100));
+
+        // Not every invalid parameter is about the number of accounts.
+        let err = make_response_error(-32602, "Invalid param: WrongSize");
+        assert!(!is_too_many_inputs_error(&err));
+
+        let err = make_response_error(-32602, "Too many inputs provided");
+        assert!(is_too_many_inputs_error(&err));
+        assert_eq!(get_max_inputs(&err), None);
+
+        let err = make_response_error(-32005, "Node is behind by 42 slots");
+        assert!(!is_too_many_inputs_error(&err));
+    }
+
+    #[test]
+    fn is_too_many_inputs_error_unstructured() {
+        let err = make_request_error(
+            "Failed to deserialize RPC error response: {\"code\":-32602,\
+            \"message\":\"Too many inputs provided; max 100\"} [missing field `data`]",
+        );
+        assert!(is_too_many_inputs_error(&err));
+        assert_eq!(get_max_inputs(&err), Some(100));
+
+        let err = make_request_error(
+            "Failed to deserialize RPC error response: {\"code\":-32602,\
+            \"message\":\"Invalid param: WrongSize\"} [missing field `data`]",
+        );
+        assert!(!is_too_many_inputs_error(&err));
+
+        // Without a code, fall back to the message.
+        let err = make_request_error("Too many inputs provided");
+        assert!(is_too_many_inputs_error(&err));
+        assert_eq!(get_max_inputs(&err), None);
+
+        let err = make_request_error(
+            "Failed to deserialize RPC error response: {\"code\":-32600,\
+            \"message\":\"Invalid request\"} [missing field `data`]",
+        );
+        assert!(!is_too_many_inputs_error(&err));
+
+        let err: ClientError = io::Error::new(io::ErrorKind::Other, "max 100").into();
+        assert!(!is_too_many_inputs_error(&err));
+        assert_eq!(get_max_inputs(&err), None);
+    }
+
+    #[test]
+    fn with_snapshot_counts_iterations() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let address = Pubkey::new_unique();
+        let account = test_account(Pubkey::new_unique(), vec![]);
+        fake_state.lock().unwrap().accounts.insert(address, account);
+
+        let mut client = SnapshotClient::new(rpc_client);
+
+        // The first time we access the account, it is not yet in the snapshot,
+        // so we need one retry.
+        let result = client
+            .with_snapshot(|mut snapshot| Ok(snapshot.get_account(&address)?.lamports))
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, 1);
+        assert_eq!(result.iterations, 2);
+        assert_eq!(result.retries(), 1);
+
+        // The second time, the client knows which accounts to query.
+        let result = client
+            .with_snapshot(|mut snapshot| Ok(snapshot.get_account(&address)?.lamports))
+            .ok()
+            .unwrap();
+        assert_eq!(result.iterations, 1);
+        assert_eq!(result.retries(), 0);
+    }
+
+    #[test]
+    fn with_snapshot_fails_on_missing_account_without_snapshot_retry() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let address = Pubkey::new_unique();
+        let account = test_account(Pubkey::new_unique(), vec![]);
+        fake_state.lock().unwrap().accounts.insert(address, account);
+
+        let mut client = SnapshotClient::new(rpc_client).with_snapshot_retry(false);
+        let mut calls = 0;
+        let result = client.with_snapshot(|mut snapshot| {
+            calls += 1;
+            Ok(snapshot.get_account(&address)?.lamports)
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // The failed call did learn about the account, so now it is included.
+        let result = client
+            .with_snapshot(|mut snapshot| Ok(snapshot.get_account(&address)?.lamports))
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, 1);
+        assert_eq!(result.iterations, 1);
+    }
+
+    #[test]
+    fn get_version_includes_feature_set() {
+        let (rpc_client, _fake_state) = new_fake_rpc_client();
+        let mut client = SnapshotClient::new(rpc_client);
+        let version = client
+            .with_snapshot(|mut snapshot| snapshot.get_version())
+            .ok()
+            .unwrap()
+            .value;
+        assert_eq!(version.solana_core, "1.9.19");
+        assert_eq!(version.feature_set, Some(1));
+    }
+
+    #[test]
+    fn with_snapshot_reuses_validator_info_within_ttl() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let identity = Pubkey::new_unique();
+        let mut client = SnapshotClient::new(rpc_client);
+        let missing_identity = |client: &mut SnapshotClient| {
+            client.with_snapshot(|_snapshot| -> crate::Result<()> {
+                Err(SnapshotError::MissingValidatorIdentity(identity))
+            })
+        };
+        let program_accounts_requests = || {
+            fake_state
+                .lock()
+                .unwrap()
+                .requests
+                .get("getProgramAccounts")
+                .copied()
+        };
+
+        // The identity has no validator info, so the first miss reloads the
+        // config accounts, and then fails.
+        assert!(missing_identity(&mut client).is_err());
+        assert_eq!(program_accounts_requests(), Some(1));
+        assert_eq!(client.validator_info_reloads(), 1);
+
+        // Within the TTL, we do not reload for the same miss.
+        assert!(missing_identity(&mut client).is_err());
+        assert_eq!(program_accounts_requests(), Some(1));
+        assert_eq!(client.validator_info_reloads(), 1);
+
+        // After it expires, we do.
+        let mut client = client.with_validator_info_ttl(Duration::ZERO);
+        assert!(missing_identity(&mut client).is_err());
+        assert_eq!(program_accounts_requests(), Some(2));
+        assert_eq!(client.validator_info_reloads(), 2);
+    }
+
+    #[test]
+    fn with_snapshot_does_not_retry_for_preloaded_accounts() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let address = Pubkey::new_unique();
+        let missing_address = Pubkey::new_unique();
+        let account = test_account(Pubkey::new_unique(), vec![]);
+        fake_state.lock().unwrap().accounts.insert(address, account);
+
+        let mut client = SnapshotClient::new(rpc_client);
+        client.preload(&[address, missing_address]);
+
+        let result = client
+            .with_snapshot(|mut snapshot| {
+                let lamports = snapshot.get_account(&address)?.lamports;
+                let missing = snapshot.get_account_if_exists(&missing_address)?;
+                Ok((lamports, missing.is_none()))
+            })
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, (1, true));
+        assert_eq!(result.iterations, 1);
+        assert_eq!(result.retries(), 0);
+    }
+
+    #[test]
+    fn with_snapshot_counts_accounts_that_changed_when_verifying_consistency() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let address = Pubkey::new_unique();
+        let account = test_account(Pubkey::new_unique(), vec![]);
+        let changed_account = Account {
+            lamports: 2,
+            ..account.clone()
+        };
+        {
+            let mut state = fake_state.lock().unwrap();
+            state.accounts.insert(address, account);
+            // The account changes right after the first read.
+            state
+                .updates_after_read
+                .push_back((address, changed_account));
+        }
+
+        let mut client = SnapshotClient::new(rpc_client).with_verify_consistency(true);
+        client.preload(&[address]);
+
+        let result = client
+            .with_snapshot(|mut snapshot| Ok(snapshot.get_account(&address)?.lamports))
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, 1);
+        assert_eq!(client.inconsistent_snapshots(), 1);
+
+        // The account no longer changes, so the next snapshot is consistent.
+        let result = client
+            .with_snapshot(|mut snapshot| Ok(snapshot.get_account(&address)?.lamports))
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, 2);
+        assert_eq!(client.inconsistent_snapshots(), 1);
+        assert_eq!(
+            fake_state.lock().unwrap().requests["getMultipleAccounts"],
+            4
+        );
+    }
+
+    #[test]
+    fn with_snapshot_ignores_sysvars_when_verifying_consistency() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let clock_account = |slot| {
+            let clock = Clock {
+                slot,
+                ..Clock::default()
+            };
+            test_account(sysvar::id(), bincode::serialize(&clock).unwrap())
+        };
+        {
+            let mut state = fake_state.lock().unwrap();
+            state.accounts.insert(sysvar::clock::id(), clock_account(1));
+            // The clock advances between every two reads.
+            state
+                .updates_after_read
+                .push_back((sysvar::clock::id(), clock_account(2)));
+        }
+
+        let mut client = SnapshotClient::new(rpc_client).with_verify_consistency(true);
+        client.preload(&[sysvar::clock::id()]);
+        let result = client
+            .with_snapshot(|mut snapshot| Ok(snapshot.get_clock()?.slot))
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, 1);
+        assert_eq!(client.inconsistent_snapshots(), 0);
+    }
+
+    #[test]
+    fn with_snapshot_keeps_the_snapshot_if_verifying_consistency_fails() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let address = Pubkey::new_unique();
+        let account = test_account(Pubkey::new_unique(), vec![]);
+        fake_state.lock().unwrap().accounts.insert(address, account);
+
+        let mut client = SnapshotClient::new(rpc_client).with_verify_consistency(true);
+        client.preload(&[address]);
+        let result = client
+            .with_snapshot(|mut snapshot| {
+                // Fail the read that verifies the snapshot.
+                fake_state.lock().unwrap().transient_errors = 1;
+                Ok(snapshot.get_account(&address)?.lamports)
+            })
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, 1);
+        assert_eq!(client.inconsistent_snapshots(), 0);
+    }
+
+    #[test]
+    fn get_watched_account_skips_missing_accounts_only_on_request() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let addresses = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let account = test_account(Pubkey::new_unique(), vec![]);
+        fake_state
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(addresses[1], account);
+
+        let mut client = SnapshotClient::new(rpc_client);
+        let mut read_balances = |skip_missing: bool| {
+            client.with_snapshot(|mut snapshot| {
+                let mut balances = Vec::new();
+                for address in &addresses {
+                    if let Some(account) = snapshot.get_watched_account(address, skip_missing)? {
+                        balances.push((*address, account.lamports));
+                    }
+                }
+                Ok((balances, snapshot.get_referenced_account_sizes()))
+            })
+        };
+
+        // By default, one missing account fails the entire snapshot.
+        assert!(read_balances(false).is_err());
+
+        // When we skip it, the other account is still reported, and the
+        // missing one shows up as not existing.
+        let (balances, sizes) = read_balances(true).ok().unwrap().value;
+        assert_eq!(balances, vec![(addresses[1], 1)]);
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0].address, addresses[0]);
+        assert_eq!(sizes[0].data_len, None);
+        assert_eq!(sizes[1].data_len, Some(0));
+    }
+
+    #[test]
+    fn with_snapshot_reports_accounts_queried_and_referenced() {
+        let (rpc_client, _fake_state) = new_fake_rpc_client();
+        let addresses = [
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        ];
+
+        let mut client = SnapshotClient::new(rpc_client);
+        client.preload(&addresses);
+
+        // We query all preloaded accounts, but only reference one of them.
+        let read_first = |client: &mut SnapshotClient| {
+            client
+                .with_snapshot(|mut snapshot| {
+                    Ok(snapshot.get_account_if_exists(&addresses[0])?.is_some())
+                })
+                .ok()
+                .unwrap()
+        };
+        let result = read_first(&mut client);
+        assert_eq!(result.accounts_queried, 3);
+        assert_eq!(result.accounts_referenced, 1);
+
+        // After the successful snapshot, we no longer query the unused accounts.
+        let result = read_first(&mut client);
+        assert_eq!(result.accounts_queried, 1);
+        assert_eq!(result.accounts_referenced, 1);
+    }
+
+    #[test]
+    fn hash_account_data_is_stable_and_fits_in_a_double() {
+        assert_eq!(hash_account_data(b"goat"), hash_account_data(b"goat"));
+        assert_ne!(hash_account_data(b"goat"), hash_account_data(b"goas"));
+        assert_ne!(hash_account_data(b""), hash_account_data(&[0]));
+        // The FNV-1a offset basis, truncated.
+        assert_eq!(
+            hash_account_data(b""),
+            0xcbf2_9ce4_8422_2325 & ((1 << 52) - 1)
+        );
+
+        let hash = hash_account_data(&[0xff; 165]);
+        assert!(hash < 1 << 52);
+        assert_eq!(hash as f64 as u64, hash);
+    }
+
+    #[test]
+    fn deserialize_bincode_names_the_address() {
+        let address = Pubkey::new_unique();
+        let err = deserialize_bincode::<Clock>(&address, &[1, 2, 3])
+            .err()
+            .unwrap();
+        assert_eq!(err.address, address);
+        assert!(err.cause.is_some());
+
+        let clock = Clock {
+            slot: 42,
+            ..Clock::default()
+        };
+        let data = bincode::serialize(&clock).unwrap();
+        assert_eq!(
+            deserialize_bincode::<Clock>(&address, &data).ok(),
+            Some(clock)
+        );
+    }
+
+    #[test]
+    fn get_slot_hashes_deserializes_sysvar() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let slot_hashes = SlotHashes::new(&[
+            (100, Hash::new_unique()),
+            (102, Hash::new_unique()),
+            (103, Hash::new_unique()),
+        ]);
+        let account = test_account(sysvar::id(), bincode::serialize(&slot_hashes).unwrap());
+        fake_state
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(sysvar::slot_hashes::id(), account);
+
+        let mut client = SnapshotClient::new(rpc_client);
+        let result = client
+            .with_snapshot(|mut snapshot| snapshot.get_slot_hashes())
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, slot_hashes);
+        assert_eq!(
+            get_slot_hashes_summary(&result.value),
+            Some(SlotHashesSummary {
+                count: 3,
+                oldest_slot: 100,
+                newest_slot: 103,
+            })
+        );
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+    struct Counter {
+        count: u64,
+        label: String,
+    }
+
+    #[test]
+    fn get_borsh_round_trips_and_reports_errors() {
+        let (rpc_client, _fake_state) = new_fake_rpc_client();
+        let counter = Counter {
+            count: 42,
+            label: "goats".to_string(),
+        };
+        let mut data = counter.try_to_vec().unwrap();
+        // Accounts are often larger than the data they hold.
+        data.extend_from_slice(&[0; 16]);
+
+        let good_address = Pubkey::new_unique();
+        let bad_address = Pubkey::new_unique();
+        let make_account = |data: Vec<u8>| test_account(Pubkey::new_unique(), data);
+        let accounts: HashMap<Pubkey, Option<Account>> = vec![
+            (good_address, Some(make_account(data))),
+            (bad_address, Some(make_account(vec![1, 2, 3]))),
+        ]
+        .into_iter()
+        .collect();
+        let mut accounts_referenced = OrderedSet::new();
+        let mut slices_referenced = OrderedSet::new();
+        let mut snapshot = Snapshot {
+            accounts: &accounts,
+            accounts_referenced: &mut accounts_referenced,
+            slices: &HashMap::new(),
+            slices_referenced: &mut slices_referenced,
+            rpc_client: &rpc_client,
+        };
+
+        assert_eq!(
+            snapshot.get_borsh::<Counter>(&good_address).ok(),
+            Some(counter)
+        );
+        assert!(matches!(
+            snapshot.get_borsh::<Counter>(&bad_address),
+            Err(SnapshotError::OtherError(..))
+        ));
+    }
+
+    #[test]
+    fn chunked_reads_are_counted_but_warned_about_once() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let addresses = [Pubkey::new_unique(), Pubkey::new_unique()];
+        {
+            let mut fake_state = fake_state.lock().unwrap();
+            fake_state.max_multiple_accounts = Some(1);
+            for address in &addresses {
+                let account = test_account(Pubkey::new_unique(), vec![]);
+                fake_state.accounts.insert(*address, account);
+            }
+        }
+
+        let mut client = SnapshotClient::new(rpc_client);
+        let read_accounts = |client: &mut SnapshotClient| {
+            client
+                .with_snapshot(|mut snapshot| {
+                    for address in &addresses {
+                        snapshot.get_account(address)?;
+                    }
+                    Ok(())
+                })
+                .ok()
+                .unwrap();
+        };
+
+        read_accounts(&mut client);
+        assert_eq!(client.chunked_reads(), 1);
+        assert_eq!(client.max_items_per_call, 1);
+        let first_warning = client.last_chunking_warning;
+        assert!(first_warning.is_some());
+
+        read_accounts(&mut client);
+        assert_eq!(client.chunked_reads(), 2);
+        assert_eq!(client.last_chunking_warning, first_warning);
+    }
+
+    #[test]
+    fn get_multiple_accounts_chunked_fails_if_one_account_is_too_many() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        fake_state.lock().unwrap().max_multiple_accounts = Some(0);
+
+        let mut client = SnapshotClient::new(rpc_client);
+        let addresses = [Pubkey::new_unique(), Pubkey::new_unique()];
+        assert!(client
+            .get_multiple_accounts_chunked(&addresses, None)
+            .is_err());
+        assert_eq!(client.max_items_per_call, 1);
+    }
+
+    #[test]
+    fn get_account_slice_only_reads_the_slice() {
+        let (rpc_client, fake_state) = new_fake_rpc_client();
+        let address = Pubkey::new_unique();
+        let account = test_account(Pubkey::new_unique(), (0..100).collect());
+        fake_state.lock().unwrap().accounts.insert(address, account);
+
+        let mut client = SnapshotClient::new(rpc_client);
+        let result = client
+            .with_snapshot(|mut snapshot| Ok(snapshot.get_account_slice(&address, 10, 4)?.to_vec()))
+            .ok()
+            .unwrap();
+        assert_eq!(result.value, vec![10, 11, 12, 13]);
+
+        // The slice is not a full account, it does not end up in the full accounts to query.
+        assert!(client.accounts_to_query.is_empty());
+        let slice = UiDataSliceConfig {
+            offset: 10,
+            length: 4,
+        };
+        assert_eq!(&client.slices_to_query[..], &[(address, slice)]);
+    }
+
+    #[test]
+    fn forget_account_removes_account_to_query() {
+        let (rpc_client, _fake_state) = new_fake_rpc_client();
+        let mut client = SnapshotClient::new(rpc_client);
+        let addresses = [
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        ];
+        for address in &addresses {
+            client.accounts_to_query.push(*address);
+        }
+
+        client.forget_account(&addresses[1]);
+        assert_eq!(
+            client.accounts_to_query.elements_vec,
+            vec![addresses[0], addresses[2]]
+        );
+        assert!(!client
+            .accounts_to_query
+            .elements_set
+            .contains(&addresses[1]));
+        assert_eq!(client.accounts_to_query.elements_set.len(), 2);
+
+        // Forgetting an account that we don't query is a no-op.
+        client.forget_account(&addresses[1]);
+        assert_eq!(client.accounts_to_query.len(), 2);
+
+        client.clear_accounts();
+        assert!(client.accounts_to_query.is_empty());
+        assert!(client.accounts_to_query.elements_set.is_empty());
+    }
+}
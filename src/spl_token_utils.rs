@@ -0,0 +1,455 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Utilities for reading SPL token accounts.
+
+use std::time::{Duration, SystemTime};
+
+use serde_json::json;
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
+use solana_client::rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType};
+use solana_client::rpc_request::RpcRequest;
+use solana_client::rpc_response::{Response as RpcResponse, RpcTokenAccountBalance};
+use solana_program::program_pack::Pack;
+use solana_sdk::account::Account;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use spl_token::state::{Account as TokenAccountState, Mint};
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily, MetricValue};
+use crate::SnapshotClient;
+
+/// Offset of the mint address in the data of an SPL token account.
+const TOKEN_ACCOUNT_MINT_OFFSET: usize = 0;
+
+/// Number of token accounts that `getTokenLargestAccounts` returns at most.
+const MAX_TOP_HOLDERS: usize = 20;
+
+/// Supply of an SPL token mint.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MintSupply {
+    /// Supply in the minimal unit of the token.
+    pub supply: u64,
+
+    /// Number of decimals of the token, i.e. the supply is `supply / 10^decimals` tokens.
+    pub decimals: u8,
+}
+
+/// The supply of a watched mint, or the reason why we could not read it.
+#[derive(Clone)]
+pub struct TokenMint {
+    pub address: Pubkey,
+    pub supply: Result<MintSupply, &'static str>,
+}
+
+/// Balance of an SPL token account.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenAccountBalance {
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+
+    /// Balance in the minimal unit of the token.
+    pub amount: u64,
+
+    /// Number of decimals of the mint.
+    pub decimals: u8,
+
+    /// Whether the mint's freeze authority froze the account.
+    pub is_frozen: bool,
+}
+
+/// The balance of a watched token account, or the reason why we could not read it.
+#[derive(Clone)]
+pub struct TokenAccount {
+    pub address: Pubkey,
+    pub balance: Result<TokenAccountBalance, &'static str>,
+}
+
+/// Balances of the largest token accounts of a mint.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenTopHolders {
+    pub mint: Pubkey,
+
+    /// Number of decimals of the mint.
+    pub decimals: u8,
+
+    /// Balances in the minimal unit of the token, largest first.
+    ///
+    /// The balance at index `i` has rank `i + 1`. There are fewer than 20 if
+    /// the mint has fewer token accounts.
+    pub amounts: Vec<u64>,
+}
+
+/// Deserialize an account that should be an SPL token mint.
+///
+/// Returns a description of the problem if the account is not a mint.
+pub fn parse_mint(account: &Account) -> Result<MintSupply, &'static str> {
+    if account.owner != spl_token::id() {
+        return Err("not owned by the token program");
+    }
+    match Mint::unpack(&account.data) {
+        Ok(mint) => Ok(MintSupply {
+            supply: mint.supply,
+            decimals: mint.decimals,
+        }),
+        Err(..) => Err("not a valid mint"),
+    }
+}
+
+/// Deserialize an account that should be an SPL token account.
+///
+/// Returns a description of the problem if the account is not a token account.
+pub fn parse_token_account(account: &Account) -> Result<TokenAccountState, &'static str> {
+    if account.owner != spl_token::id() {
+        return Err("not owned by the token program");
+    }
+    TokenAccountState::unpack(&account.data).map_err(|_| "not a valid token account")
+}
+
+/// Return the `getProgramAccounts` filters that select the token accounts of the mint.
+pub fn get_token_holder_filters(mint: &Pubkey) -> Vec<RpcFilterType> {
+    vec![
+        RpcFilterType::DataSize(TokenAccountState::LEN as u64),
+        RpcFilterType::Memcmp(Memcmp {
+            offset: TOKEN_ACCOUNT_MINT_OFFSET,
+            bytes: MemcmpEncodedBytes::Base58(mint.to_string()),
+            encoding: None,
+        }),
+    ]
+}
+
+/// Count the token accounts of the mint, including accounts with a zero balance.
+///
+/// This calls `getProgramAccounts` on the token program, which is expensive,
+/// and which some RPC providers disable. We request an empty data slice, so
+/// the RPC only returns the addresses.
+pub fn count_token_holders(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    mint: &Pubkey,
+) -> std::result::Result<u64, Error> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(get_token_holder_filters(mint)),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            data_slice: Some(UiDataSliceConfig {
+                offset: 0,
+                length: 0,
+            }),
+            commitment: Some(commitment),
+        },
+        with_context: None,
+    };
+    match rpc_client.get_program_accounts_with_config(&spl_token::id(), config) {
+        Ok(accounts) => Ok(accounts.len() as u64),
+        Err(err) => {
+            log::warn!(
+                "Failed to count token holders of {}, the RPC node may not allow getProgramAccounts.",
+                mint
+            );
+            Err(err.into())
+        }
+    }
+}
+
+/// Call `getTokenLargestAccounts`, which returns the 20 largest token accounts of the mint.
+///
+/// The version of `solana_client` that we use has no method for this, so we
+/// send the request ourselves. Like `getProgramAccounts`, this is expensive
+/// for the RPC node.
+pub fn get_token_largest_accounts(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    mint: &Pubkey,
+) -> std::result::Result<Vec<RpcTokenAccountBalance>, Error> {
+    let response: RpcResponse<Vec<RpcTokenAccountBalance>> = rpc_client.send(
+        RpcRequest::Custom {
+            method: "getTokenLargestAccounts",
+        },
+        json!([mint.to_string(), commitment]),
+    )?;
+    Ok(response.value)
+}
+
+/// Turn the response of `getTokenLargestAccounts` into balances ordered by rank.
+///
+/// The RPC returns the accounts ordered already, but we sort them anyway, so
+/// the ranks do not depend on that. Amounts that are not an integer, which
+/// the RPC does not return, are skipped.
+pub fn get_token_top_holders(mint: Pubkey, accounts: &[RpcTokenAccountBalance]) -> TokenTopHolders {
+    let mut amounts: Vec<u64> = accounts
+        .iter()
+        .filter_map(|account| account.amount.amount.parse().ok())
+        .collect();
+    amounts.sort_unstable_by(|a, b| b.cmp(a));
+    amounts.truncate(MAX_TOP_HOLDERS);
+    TokenTopHolders {
+        mint,
+        // All accounts of a mint have the same decimals, without accounts
+        // there are no balances to scale.
+        decimals: accounts
+            .first()
+            .map_or(0, |account| account.amount.decimals),
+        amounts,
+    }
+}
+
+/// Reports the number of token accounts of the mints of `--count-token-holders`.
+pub struct TokenHoldersSource<'a> {
+    pub mints: &'a [Pubkey],
+    pub interval: Duration,
+}
+
+impl<'a> MetricSource for TokenHoldersSource<'a> {
+    fn name(&self) -> &'static str {
+        "token holders"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &["solana_token_holders_total"]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let mut metrics = Vec::with_capacity(self.mints.len());
+        for mint in self.mints {
+            let count = count_token_holders(client.rpc_client(), client.commitment(), mint)?;
+            metrics.push(
+                Metric::new(count)
+                    .with_label("mint", mint.to_string())
+                    .at(core.produced_at),
+            );
+        }
+        Ok(vec![MetricFamily {
+            name: "solana_token_holders_total",
+            help: "Number of token accounts of the SPL token mint, including empty ones",
+            type_: "gauge",
+            metrics,
+        }])
+    }
+}
+
+/// Reports the balances of the largest token accounts of the mints of `--mint-top-holders`.
+pub struct TokenTopHoldersSource<'a> {
+    pub mints: &'a [Pubkey],
+    pub interval: Duration,
+}
+
+impl<'a> MetricSource for TokenTopHoldersSource<'a> {
+    fn name(&self) -> &'static str {
+        "token top holders"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &["solana_token_top_holder_balance"]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let mut top_holders = Vec::with_capacity(self.mints.len());
+        for mint in self.mints {
+            let accounts =
+                get_token_largest_accounts(client.rpc_client(), client.commitment(), mint)?;
+            top_holders.push(get_token_top_holders(*mint, &accounts));
+        }
+        Ok(get_token_top_holder_families(
+            &top_holders,
+            core.produced_at,
+        ))
+    }
+}
+
+/// Return the metric family for the balances of the largest token accounts.
+fn get_token_top_holder_families(
+    top_holders: &[TokenTopHolders],
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    vec![MetricFamily {
+        name: "solana_token_top_holder_balance",
+        help: "Balance of the token account with this rank among the largest of the SPL token mint",
+        type_: "gauge",
+        metrics: top_holders
+            .iter()
+            .flat_map(|top_holders| {
+                top_holders.amounts.iter().enumerate().map(|(i, amount)| {
+                    Metric::new(MetricValue::Fixed {
+                        amount: *amount,
+                        decimals: top_holders.decimals,
+                    })
+                    .with_label("mint", top_holders.mint.to_string())
+                    .with_label("rank", (i + 1).to_string())
+                    .at(produced_at)
+                })
+            })
+            .collect(),
+    }]
+}
+
+#[cfg(test)]
+mod test {
+    use solana_account_decoder::parse_token::UiTokenAmount;
+
+    use super::*;
+    use crate::fake_rpc::test_account;
+
+    /// Return the serialized form of a mint with the given supply and decimals.
+    fn make_mint_data(supply: u64, decimals: u8) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Mint::LEN);
+        // Mint authority, a `COption<Pubkey>` that is set.
+        data.extend_from_slice(&[1, 0, 0, 0]);
+        data.extend_from_slice(&[7; 32]);
+        data.extend_from_slice(&supply.to_le_bytes());
+        data.push(decimals);
+        // Is initialized.
+        data.push(1);
+        // Freeze authority, a `COption<Pubkey>` that is not set.
+        data.extend_from_slice(&[0; 36]);
+        data
+    }
+
+    /// Return the serialized form of a token account.
+    fn make_token_account_data(mint: Pubkey, owner: Pubkey, amount: u64, state: u8) -> Vec<u8> {
+        let mut data = Vec::with_capacity(TokenAccountState::LEN);
+        data.extend_from_slice(mint.as_ref());
+        data.extend_from_slice(owner.as_ref());
+        data.extend_from_slice(&amount.to_le_bytes());
+        // Delegate, a `COption<Pubkey>` that is not set.
+        data.extend_from_slice(&[0; 36]);
+        data.push(state);
+        // Is native, a `COption<u64>` that is not set.
+        data.extend_from_slice(&[0; 12]);
+        // Delegated amount.
+        data.extend_from_slice(&[0; 8]);
+        // Close authority, a `COption<Pubkey>` that is not set.
+        data.extend_from_slice(&[0; 36]);
+        data
+    }
+
+    #[test]
+    fn parse_mint_reads_supply_and_decimals() {
+        let account = test_account(spl_token::id(), make_mint_data(123_456_789, 6));
+        assert_eq!(
+            parse_mint(&account),
+            Ok(MintSupply {
+                supply: 123_456_789,
+                decimals: 6,
+            }),
+        );
+    }
+
+    #[test]
+    fn parse_mint_rejects_other_owner() {
+        let account = test_account(Pubkey::new_unique(), make_mint_data(1, 6));
+        assert!(parse_mint(&account).is_err());
+    }
+
+    #[test]
+    fn parse_mint_rejects_uninitialized_or_wrong_size() {
+        let mut data = make_mint_data(1, 6);
+        data[45] = 0;
+        assert!(parse_mint(&test_account(spl_token::id(), data)).is_err());
+
+        let account = test_account(spl_token::id(), vec![0; 10]);
+        assert!(parse_mint(&account).is_err());
+    }
+
+    #[test]
+    fn parse_token_account_reads_balance() {
+        let mint = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        // State 1 is initialized.
+        let data = make_token_account_data(mint, owner, 42_000, 1);
+        let token_account = parse_token_account(&test_account(spl_token::id(), data)).unwrap();
+        assert_eq!(token_account.mint, mint);
+        assert_eq!(token_account.owner, owner);
+        assert_eq!(token_account.amount, 42_000);
+        assert!(!token_account.is_frozen());
+
+        // State 2 is frozen.
+        let data = make_token_account_data(mint, owner, 42_000, 2);
+        let token_account = parse_token_account(&test_account(spl_token::id(), data)).unwrap();
+        assert!(token_account.is_frozen());
+    }
+
+    #[test]
+    fn parse_token_account_rejects_mint() {
+        let account = test_account(spl_token::id(), make_mint_data(1, 6));
+        assert!(parse_token_account(&account).is_err());
+    }
+
+    #[test]
+    fn get_token_holder_filters_match_mint_of_token_accounts() {
+        let mint = Pubkey::new_unique();
+        let filters = get_token_holder_filters(&mint);
+        assert_eq!(filters[0], RpcFilterType::DataSize(165));
+        match &filters[1] {
+            RpcFilterType::Memcmp(memcmp) => {
+                // The mint is the first field of a token account.
+                let data = make_token_account_data(mint, Pubkey::new_unique(), 1, 1);
+                let bytes = memcmp.bytes().unwrap();
+                assert_eq!(&data[memcmp.offset..memcmp.offset + 32], &bytes[..]);
+            }
+            other => panic!("Expected a memcmp filter, got {:?}.", other),
+        }
+    }
+
+    fn make_token_account_balance(amount: u64, decimals: u8) -> RpcTokenAccountBalance {
+        RpcTokenAccountBalance {
+            address: Pubkey::new_unique().to_string(),
+            amount: UiTokenAmount {
+                ui_amount: None,
+                decimals,
+                amount: amount.to_string(),
+                ui_amount_string: String::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn get_token_top_holders_ranks_balances() {
+        let mint = Pubkey::new_unique();
+        let accounts = [
+            make_token_account_balance(5_000, 3),
+            make_token_account_balance(9_000, 3),
+            make_token_account_balance(0, 3),
+        ];
+        // Fewer than 20 holders only yields the ranks that exist.
+        assert_eq!(
+            get_token_top_holders(mint, &accounts),
+            TokenTopHolders {
+                mint,
+                decimals: 3,
+                amounts: vec![9_000, 5_000, 0],
+            }
+        );
+
+        let accounts: Vec<_> = (1..=25)
+            .map(|amount| make_token_account_balance(amount, 6))
+            .collect();
+        let top_holders = get_token_top_holders(mint, &accounts);
+        assert_eq!(top_holders.amounts.len(), 20);
+        assert_eq!(top_holders.amounts[0], 25);
+        assert_eq!(top_holders.amounts[19], 6);
+
+        assert_eq!(get_token_top_holders(mint, &[]).amounts, Vec::<u64>::new());
+    }
+}
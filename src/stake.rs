@@ -0,0 +1,205 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Activation state of individual stake accounts, and of the cluster as a whole.
+
+use serde_json::json;
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcEpochConfig;
+use solana_client::rpc_request::RpcRequest;
+use solana_client::rpc_response::{RpcStakeActivation, StakeActivationState};
+use solana_program::clock::Epoch;
+use solana_sdk::account::Account;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::sysvar::stake_history::StakeHistory;
+
+use crate::error::Error;
+use crate::token::Lamports;
+
+/// Activation of a stake account, split by the phase that the stake is in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StakeActivation {
+    pub stake_account: Pubkey,
+
+    pub state: StakeActivationState,
+
+    /// Stake that is effective in the current epoch.
+    ///
+    /// While deactivating, this is the stake that is still effective.
+    pub active: Lamports,
+
+    /// Stake that is becoming effective over the coming epochs.
+    pub activating: Lamports,
+
+    /// Effective stake that is being deactivated.
+    pub deactivating: Lamports,
+}
+
+/// Cluster-wide stake of the most recent epoch in the `StakeHistory` sysvar.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StakeHistoryTotals {
+    pub epoch: Epoch,
+
+    /// Stake that was effective in the epoch.
+    pub effective: Lamports,
+
+    /// Stake that was not yet fully warmed up.
+    pub activating: Lamports,
+
+    /// Stake that was requested to cool down, but not yet fully deactivated.
+    pub deactivating: Lamports,
+}
+
+/// Return the totals of the most recent epoch, or `None` if the history is empty.
+///
+/// The sysvar stores the epochs in descending order, so the first entry is the most recent one.
+pub fn get_latest_stake_history_totals(stake_history: &StakeHistory) -> Option<StakeHistoryTotals> {
+    stake_history
+        .first()
+        .map(|(epoch, entry)| StakeHistoryTotals {
+            epoch: *epoch,
+            effective: Lamports(entry.effective),
+            activating: Lamports(entry.activating),
+            deactivating: Lamports(entry.deactivating),
+        })
+}
+
+/// Return the label value for the activation state.
+pub fn get_state_name(state: &StakeActivationState) -> &'static str {
+    match state {
+        StakeActivationState::Activating => "activating",
+        StakeActivationState::Active => "active",
+        StakeActivationState::Deactivating => "deactivating",
+        StakeActivationState::Inactive => "inactive",
+    }
+}
+
+/// All activation states, in the order in which we report them.
+pub static STAKE_ACTIVATION_STATES: [StakeActivationState; 4] = [
+    StakeActivationState::Active,
+    StakeActivationState::Activating,
+    StakeActivationState::Deactivating,
+    StakeActivationState::Inactive,
+];
+
+/// Return whether the account is owned by the stake program.
+///
+/// `getStakeActivation` fails for any other account, so we do not call it for those.
+pub fn is_stake_account(account: &Account) -> bool {
+    account.owner == solana_sdk::stake::program::id()
+}
+
+/// Split the response of `getStakeActivation` into the stake per phase.
+///
+/// The RPC node only reports the active and inactive stake. The inactive
+/// stake is the activating stake while activating, and the active stake is the
+/// deactivating stake while deactivating, because a stake account is never
+/// activating and deactivating at the same time.
+pub fn get_stake_activation_from_response(
+    stake_account: Pubkey,
+    response: &RpcStakeActivation,
+) -> StakeActivation {
+    let (activating, deactivating) = match response.state {
+        StakeActivationState::Activating => (response.inactive, 0),
+        StakeActivationState::Deactivating => (0, response.active),
+        StakeActivationState::Active | StakeActivationState::Inactive => (0, 0),
+    };
+    StakeActivation {
+        stake_account,
+        state: response.state.clone(),
+        active: Lamports(response.active),
+        activating: Lamports(activating),
+        deactivating: Lamports(deactivating),
+    }
+}
+
+/// Call `getStakeActivation` for the current epoch.
+///
+/// This is a separate RPC call, so it is not consistent with the snapshot.
+/// Like for the inflation rewards, we make the call ourselves, to read it at
+/// `commitment` rather than at the commitment of the client.
+pub fn get_stake_activation(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    stake_account: Pubkey,
+) -> std::result::Result<StakeActivation, Error> {
+    let response: RpcStakeActivation = rpc_client.send(
+        RpcRequest::GetStakeActivation,
+        json!([
+            stake_account.to_string(),
+            RpcEpochConfig {
+                epoch: None,
+                commitment: Some(commitment),
+            }
+        ]),
+    )?;
+    Ok(get_stake_activation_from_response(stake_account, &response))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get_activation(state: StakeActivationState, active: u64, inactive: u64) -> StakeActivation {
+        let response = RpcStakeActivation {
+            state,
+            active,
+            inactive,
+        };
+        get_stake_activation_from_response(Pubkey::default(), &response)
+    }
+
+    #[test]
+    fn get_stake_activation_from_response_splits_by_phase() {
+        let activation = get_activation(StakeActivationState::Activating, 3, 7);
+        assert_eq!(activation.active, Lamports(3));
+        assert_eq!(activation.activating, Lamports(7));
+        assert_eq!(activation.deactivating, Lamports(0));
+
+        let activation = get_activation(StakeActivationState::Active, 10, 0);
+        assert_eq!(activation.active, Lamports(10));
+        assert_eq!(activation.activating, Lamports(0));
+        assert_eq!(activation.deactivating, Lamports(0));
+
+        let activation = get_activation(StakeActivationState::Deactivating, 6, 4);
+        assert_eq!(activation.active, Lamports(6));
+        assert_eq!(activation.activating, Lamports(0));
+        assert_eq!(activation.deactivating, Lamports(6));
+
+        let activation = get_activation(StakeActivationState::Inactive, 0, 10);
+        assert_eq!(activation.active, Lamports(0));
+        assert_eq!(activation.activating, Lamports(0));
+        assert_eq!(activation.deactivating, Lamports(0));
+        assert_eq!(get_state_name(&activation.state), "inactive");
+    }
+
+    #[test]
+    fn get_latest_stake_history_totals_uses_most_recent_epoch() {
+        use solana_sdk::stake_history::StakeHistoryEntry;
+
+        let mut stake_history = StakeHistory::default();
+        assert_eq!(get_latest_stake_history_totals(&stake_history), None);
+
+        let entry = |effective, activating, deactivating| StakeHistoryEntry {
+            effective,
+            activating,
+            deactivating,
+        };
+        stake_history.add(41, entry(100, 5, 0));
+        stake_history.add(42, entry(105, 2, 3));
+
+        // Round-trip through the account data format that we read from the snapshot.
+        let data = bincode::serialize(&stake_history).unwrap();
+        let stake_history: StakeHistory = bincode::deserialize(&data).unwrap();
+        assert_eq!(
+            get_latest_stake_history_totals(&stake_history),
+            Some(StakeHistoryTotals {
+                epoch: 42,
+                effective: Lamports(105),
+                activating: Lamports(2),
+                deactivating: Lamports(3),
+            })
+        );
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! A summary of the run, which we print when we exit.
+//!
+//! With `--once` this is the result of the single poll, after a shutdown
+//! signal it covers the whole run. The json output is meant for CI jobs that
+//! need to parse the result.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use solana_program::clock::Slot;
+
+use crate::snapshot::OutputMode;
+use crate::Metrics;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// Number of polls, successful or not.
+    pub polls: u64,
+
+    /// Number of polls that failed.
+    pub errors: u64,
+
+    /// Slot of the last successful poll, `None` if no poll succeeded.
+    pub last_slot: Option<Slot>,
+
+    /// Time since start.
+    pub uptime_seconds: f64,
+}
+
+impl Summary {
+    pub fn new(metrics: &Metrics, uptime: Duration) -> Summary {
+        Summary {
+            polls: metrics.polls,
+            errors: metrics.errors,
+            last_slot: match (&metrics.core, metrics.ready) {
+                (Some(core), true) => Some(core.clock.slot),
+                _ => None,
+            },
+            uptime_seconds: uptime.as_secs_f64(),
+        }
+    }
+}
+
+/// Print the summary to stdout.
+pub fn print_summary(summary: &Summary, output_mode: OutputMode) {
+    match output_mode {
+        OutputMode::Text => {
+            let last_slot = match summary.last_slot {
+                Some(slot) => slot.to_string(),
+                None => "none".to_string(),
+            };
+            println!("{:<10} {}", "Polls", summary.polls);
+            println!("{:<10} {}", "Errors", summary.errors);
+            println!("{:<10} {}", "Last slot", last_slot);
+            println!("{:<10} {:.1}s", "Uptime", summary.uptime_seconds);
+        }
+        OutputMode::Json => {
+            let json = serde_json::to_string_pretty(summary)
+                .expect("Serializing the summary to json does not fail.");
+            println!("{}", json);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metric_source::CoreState;
+
+    #[test]
+    fn summary_json_contains_expected_keys() {
+        let metrics = Metrics {
+            polls: 3,
+            errors: 1,
+            ready: true,
+            core: Some(CoreState::default()),
+            ..Metrics::default()
+        };
+        let summary = Summary::new(&metrics, Duration::from_millis(12_500));
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "polls": 3,
+                "errors": 1,
+                "last_slot": 0,
+                "uptime_seconds": 12.5,
+            })
+        );
+
+        // Before the first successful poll, there is no slot to report.
+        let summary = Summary::new(&Metrics::default(), Duration::from_secs(1));
+        assert_eq!(summary.last_slot, None);
+    }
+}
@@ -0,0 +1,81 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Writing the metrics to a file, for the node_exporter textfile collector.
+//!
+//! The collector may read the file at any time, so we must never expose a
+//! partially written file. We write to a temporary file in the same
+//! directory first, and then rename it over the target, which is atomic.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::prometheus::{MetricsFormat, WriteOptions};
+use crate::Metrics;
+
+/// Return the path of the temporary file to write before renaming it to `path`.
+///
+/// The collector only reads files that end in `.prom`, so it ignores this one.
+fn get_temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(OsString::new);
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+/// Replace the contents of the file at `path`, such that readers never observe a partial file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = get_temp_path(path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// Write the metrics in the Prometheus text format to the file at `path`.
+///
+/// The textfile collector rejects metrics with timestamps, so we always omit them.
+pub fn write_textfile(path: &Path, metrics: &Metrics, options: &WriteOptions) -> io::Result<()> {
+    let options = WriteOptions {
+        format: MetricsFormat::Prometheus,
+        timestamps: false,
+        prefix: options.prefix.clone(),
+        labels: options.labels.clone(),
+        disabled_families: options.disabled_families.clone(),
+        sol_decimals: options.sol_decimals,
+    };
+    let mut out: Vec<u8> = Vec::new();
+    metrics.write_prometheus(&mut out, &options)?;
+    write_atomically(path, &out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_textfile_replaces_file_and_removes_temp_file() {
+        let dir = std::env::temp_dir().join(format!("hydrant-textfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hydrant.prom");
+        fs::write(&path, "stale contents").unwrap();
+
+        let metrics = Metrics::default();
+        write_textfile(&path, &metrics, &WriteOptions::default()).unwrap();
+
+        let mut expected: Vec<u8> = Vec::new();
+        let options = WriteOptions {
+            timestamps: false,
+            ..WriteOptions::default()
+        };
+        metrics.write_prometheus(&mut expected, &options).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        assert!(!get_temp_path(&path).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
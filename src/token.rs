@@ -2,6 +2,60 @@ use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
 use serde::Serialize;
 use std::fmt;
 
+/// Format an amount of minimal units as a decimal number of tokens with `decimals` decimals.
+///
+/// This is the same as the `Display` impl of the token types generated by
+/// [`impl_token`], but it supports an arbitrary number of decimals, and it
+/// does not add a symbol. For example, 1500 with 3 decimals formats as `1.500`.
+pub fn format_fixed_point(amount: u64, decimals: u8) -> String {
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return amount.to_string();
+    }
+
+    // Pad with leading zeros so there is at least one digit before the decimal
+    // point, then insert the decimal point.
+    let digits = format!("{:0>width$}", amount, width = decimals + 1);
+    let (integer_part, fractional_part) = digits.split_at(digits.len() - decimals);
+    format!("{}.{}", integer_part, fractional_part)
+}
+
+/// Round an amount of minimal units with `decimals` decimals to `target_decimals` decimals.
+///
+/// Returns the rounded amount, in units with `target_decimals` decimals, and
+/// those decimals. We round half away from zero. Because we round the integer
+/// amount rather than a float, the result is exact when formatted with
+/// [`format_fixed_point`]. An amount that already has at most `target_decimals`
+/// decimals is returned unchanged.
+pub fn round_fixed_point(amount: u64, decimals: u8, target_decimals: u8) -> (u64, u8) {
+    if target_decimals >= decimals {
+        return (amount, decimals);
+    }
+    let divisor = 10u64.pow((decimals - target_decimals) as u32);
+    let quotient = amount / divisor;
+    let remainder = amount % divisor;
+    if remainder >= divisor - remainder {
+        (quotient + 1, target_decimals)
+    } else {
+        (quotient, target_decimals)
+    }
+}
+
+/// Convert an amount of minimal units with `decimals` decimals to a float.
+///
+/// We convert the whole tokens and the remaining units separately. Dividing
+/// the amount as a float directly would first round amounts above
+/// 2<sup>53</sup> units, and then round again in the division.
+///
+/// Prefer exact fixed-point output where possible, see [`format_fixed_point`].
+pub fn fixed_point_to_f64(amount: u64, decimals: u8) -> f64 {
+    match 10u64.checked_pow(decimals as u32) {
+        Some(scale) => (amount / scale) as f64 + (amount % scale) as f64 / scale as f64,
+        // The scale does not fit in a u64, so the amount is less than one token.
+        None => amount as f64 / 10f64.powi(decimals as i32),
+    }
+}
+
 /// Generate a token type that wraps the minimal unit of the token, it’s
 /// “Lamport”. The symbol is for 10<sup>9</sup> of its minimal units and is
 /// only used for `Debug` and `Display` printing.
@@ -41,6 +95,28 @@ macro_rules! impl_token {
             }
         }
 
+        /// Add two amounts. Like integer addition, this panics on overflow in debug builds.
+        impl std::ops::Add for $TokenLamports {
+            type Output = $TokenLamports;
+            fn add(self, other: $TokenLamports) -> $TokenLamports {
+                $TokenLamports(self.0 + other.0)
+            }
+        }
+
+        /// Subtract two amounts. Like integer subtraction, this panics on overflow in debug builds.
+        impl std::ops::Sub for $TokenLamports {
+            type Output = $TokenLamports;
+            fn sub(self, other: $TokenLamports) -> $TokenLamports {
+                $TokenLamports(self.0 - other.0)
+            }
+        }
+
+        impl std::iter::Sum for $TokenLamports {
+            fn sum<I: Iterator<Item = $TokenLamports>>(iter: I) -> $TokenLamports {
+                iter.fold($TokenLamports(0), |total, amount| total + amount)
+            }
+        }
+
         /// Parse a numeric string as an amount of Lamports, i.e., with 9 digit precision.
         ///
         /// Note that this parses the Lamports amount divided by 10<sup>9</sup>,
@@ -98,3 +174,109 @@ macro_rules! impl_token {
 }
 
 impl_token!(Lamports, "SOL", decimals = 9);
+
+/// Convert an amount of lamports to SOL, as a float, see [`fixed_point_to_f64`].
+pub fn lamports_to_sol(lamports: u64) -> f64 {
+    fixed_point_to_f64(lamports, 9)
+}
+
+impl Lamports {
+    /// Return the amount in SOL as a float, see [`lamports_to_sol`].
+    pub fn to_sol_f64(self) -> f64 {
+        lamports_to_sol(self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_fixed_point_matches_lamports_display() {
+        for amount in [
+            0,
+            1,
+            999_999_999,
+            1_000_000_000,
+            1_234_567_890_123,
+            u64::MAX,
+        ] {
+            assert_eq!(
+                format!("{} SOL", format_fixed_point(amount, 9)),
+                Lamports(amount).to_string(),
+            );
+        }
+    }
+
+    #[test]
+    fn format_fixed_point_supports_arbitrary_decimals() {
+        assert_eq!(format_fixed_point(1500, 0), "1500");
+        assert_eq!(format_fixed_point(1500, 3), "1.500");
+        assert_eq!(format_fixed_point(5, 6), "0.000005");
+        assert_eq!(format_fixed_point(u64::MAX, 20), "0.18446744073709551615");
+        assert_eq!(format_fixed_point(1, 25), "0.0000000000000000000000001");
+    }
+
+    #[test]
+    fn round_fixed_point_rounds_half_away_from_zero() {
+        assert_eq!(round_fixed_point(1_234_567_890, 9, 3), (1_235, 3));
+        assert_eq!(format_fixed_point(1_234_567_890, 9), "1.234567890");
+        assert_eq!(format_fixed_point(1_235, 3), "1.235");
+        assert_eq!(round_fixed_point(1_234_499_999, 9, 3), (1_234, 3));
+        assert_eq!(round_fixed_point(1_234_500_000, 9, 3), (1_235, 3));
+        assert_eq!(round_fixed_point(499_999_999, 9, 0), (0, 0));
+        assert_eq!(round_fixed_point(u64::MAX, 9, 0), (18_446_744_074, 0));
+        // Rounding to more decimals than the amount has does not change it.
+        assert_eq!(round_fixed_point(1_500, 3, 9), (1_500, 3));
+    }
+
+    #[test]
+    fn lamports_to_sol_matches_lamports_display() {
+        assert_eq!(lamports_to_sol(1), 1e-9);
+        assert_eq!(lamports_to_sol(999_999_999), 0.999_999_999);
+        assert_eq!(Lamports(1_500_000_000).to_sol_f64(), 1.5);
+
+        for amount in [1, 999_999_999, u64::MAX] {
+            // The integer part is exact, and matches the display.
+            let display = Lamports(amount).to_string();
+            let integer_part = display.split('.').next().unwrap();
+            assert_eq!(lamports_to_sol(amount).trunc().to_string(), integer_part);
+        }
+    }
+
+    #[test]
+    fn fixed_point_to_f64_supports_arbitrary_decimals() {
+        assert_eq!(fixed_point_to_f64(235, 3), 0.235);
+        assert_eq!(fixed_point_to_f64(1_500, 3), 1.5);
+        assert_eq!(fixed_point_to_f64(1_500, 0), 1500.0);
+        // 10^25 does not fit in a u64.
+        assert!((fixed_point_to_f64(5, 25) - 5e-25).abs() < 1e-40);
+    }
+
+    #[test]
+    fn lamports_add_sub_and_sum() {
+        assert_eq!(Lamports(2) + Lamports(3), Lamports(5));
+        assert_eq!(Lamports(5) - Lamports(3), Lamports(2));
+
+        let amounts = vec![Lamports(1), Lamports(10), Lamports(100)];
+        assert_eq!(amounts.into_iter().sum::<Lamports>(), Lamports(111));
+        assert_eq!(
+            Vec::<Lamports>::new().into_iter().sum::<Lamports>(),
+            Lamports(0)
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn lamports_add_panics_on_overflow() {
+        let _ = Lamports(u64::MAX) + Lamports(1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn lamports_sub_panics_on_overflow() {
+        let _ = Lamports(0) - Lamports(1);
+    }
+}
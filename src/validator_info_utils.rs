@@ -5,8 +5,8 @@ use std::collections::{HashMap, HashSet};
 
 use serde::{Deserialize, Serialize};
 use solana_account_decoder::validator_info;
-use solana_client::rpc_client::RpcClient;
 use solana_config_program::ConfigKeys;
+use solana_sdk::account::Account;
 use solana_sdk::pubkey::Pubkey;
 
 use crate::error::{Error, SerializationError};
@@ -33,7 +33,7 @@ pub struct ValidatorInfo {
 /// address or any other validator property; to find the config account for a
 /// given validator, we have to deserialize all config accounts that exist, and
 /// filter for the one that belongs to a given identity account. See
-/// [`get_validator_info_accounts`] for that.
+/// [`get_validator_info_mapping`] for that.
 pub fn deserialize_validator_info(
     config_address: Pubkey,
     account_data: &[u8],
@@ -76,7 +76,7 @@ pub fn deserialize_validator_info(
     Ok((validator_identity, validator_info))
 }
 
-/// Return a map from validator identity account to config account.
+/// Build the map from identity account to config account, from all accounts of the config program.
 ///
 /// To get the validator info (the validator metadata, such as name and Keybase
 /// username), we have to extract that from the config account that stores the
@@ -85,10 +85,9 @@ pub fn deserialize_validator_info(
 /// is to enumerate all config accounts and then find the one you are looking
 /// for. This function builds a map from identity account to config account, so
 /// we only have to enumerate once.
-pub fn get_validator_info_accounts(rpc_client: &mut RpcClient) -> Result<HashMap<Pubkey, Pubkey>> {
-    use solana_sdk::config::program as config_program;
-
-    let all_config_accounts = rpc_client.get_program_accounts(&config_program::id())?;
+pub fn get_validator_info_mapping(
+    all_config_accounts: &[(Pubkey, Account)],
+) -> HashMap<Pubkey, Pubkey> {
     let mut mapping = HashMap::new();
 
     // Due to the structure of validator info (config accounts pointing to identity
@@ -98,7 +97,7 @@ pub fn get_validator_info_accounts(rpc_client: &mut RpcClient) -> Result<HashMap
     // ignore all validator infos for that identity.
     let mut bad_identities = HashSet::new();
 
-    for (config_addr, account) in &all_config_accounts {
+    for (config_addr, account) in all_config_accounts {
         if let Ok((validator_identity, _info)) =
             deserialize_validator_info(*config_addr, &account.data)
         {
@@ -121,7 +120,7 @@ pub fn get_validator_info_accounts(rpc_client: &mut RpcClient) -> Result<HashMap
         mapping.remove(bad_identity);
     }
 
-    Ok(mapping)
+    mapping
 }
 
 #[cfg(test)]
@@ -0,0 +1,419 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Cluster-wide stake and vote account statistics.
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::RpcGetVoteAccountsConfig;
+use solana_client::rpc_response::RpcVoteAccountStatus;
+use solana_program::clock::Slot;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::Error;
+use crate::metric_source::{CoreState, MetricSource};
+use crate::prometheus::{Metric, MetricFamily};
+use crate::token::Lamports;
+use crate::SnapshotClient;
+
+/// Stake and number of vote accounts, split by whether the vote account is delinquent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClusterStake {
+    /// Stake delegated to vote accounts that are voting.
+    pub active_stake: Lamports,
+
+    /// Stake delegated to vote accounts that are delinquent.
+    pub delinquent_stake: Lamports,
+
+    /// Number of vote accounts that are voting.
+    pub current_vote_accounts: u64,
+
+    /// Number of vote accounts that are delinquent.
+    pub delinquent_vote_accounts: u64,
+}
+
+/// Number of validators with a vote account, split by whether they are delinquent.
+///
+/// A validator is identified by its node pubkey. Not every node in gossip
+/// votes, so this can be lower than the number of cluster nodes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValidatorCounts {
+    /// Number of validators with a current or delinquent vote account.
+    pub total: u64,
+
+    /// Number of validators that have only delinquent vote accounts.
+    pub delinquent: u64,
+}
+
+/// Voting status of a single vote account.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VoteAccountStatus {
+    pub vote_account: Pubkey,
+
+    /// Whether the vote account is in the delinquent list.
+    pub is_delinquent: bool,
+
+    /// Number of slots between the current slot and the last slot the vote account voted on.
+    pub last_vote_slot_lag: u64,
+}
+
+/// Read the current and delinquent vote accounts.
+///
+/// The response contains every vote account in the cluster, so it is large,
+/// and it only changes meaningfully per epoch. Poll it less often than the
+/// base poll.
+pub fn get_vote_accounts(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+) -> std::result::Result<RpcVoteAccountStatus, Error> {
+    Ok(rpc_client.get_vote_accounts_with_commitment(commitment)?)
+}
+
+/// Read the status of the watched vote accounts, with one small request per vote account.
+///
+/// Unlike [`get_vote_accounts`], this is cheap enough to do on every poll.
+/// Vote accounts that the RPC node does not know are left out.
+pub fn get_watched_vote_accounts(
+    rpc_client: &RpcClient,
+    commitment: CommitmentConfig,
+    vote_accounts: &[Pubkey],
+    current_slot: Slot,
+) -> std::result::Result<Vec<VoteAccountStatus>, Error> {
+    let mut result = Vec::new();
+    for vote_account in vote_accounts {
+        let status = rpc_client.get_vote_accounts_with_config(RpcGetVoteAccountsConfig {
+            vote_pubkey: Some(vote_account.to_string()),
+            commitment: Some(commitment),
+            ..RpcGetVoteAccountsConfig::default()
+        })?;
+        result.extend(get_vote_account_status(&status, vote_account, current_slot));
+    }
+    Ok(result)
+}
+
+/// Sum the activated stake of the current and delinquent vote accounts.
+pub fn get_cluster_stake(status: &RpcVoteAccountStatus) -> ClusterStake {
+    ClusterStake {
+        active_stake: Lamports(status.current.iter().map(|v| v.activated_stake).sum()),
+        delinquent_stake: Lamports(status.delinquent.iter().map(|v| v.activated_stake).sum()),
+        current_vote_accounts: status.current.len() as u64,
+        delinquent_vote_accounts: status.delinquent.len() as u64,
+    }
+}
+
+/// Count the distinct validators of the current and delinquent vote accounts.
+///
+/// A validator can have multiple vote accounts, it only counts as delinquent
+/// if none of them is current.
+pub fn get_validator_counts(status: &RpcVoteAccountStatus) -> ValidatorCounts {
+    let current: HashSet<&str> = status
+        .current
+        .iter()
+        .map(|info| &info.node_pubkey[..])
+        .collect();
+    let delinquent: HashSet<&str> = status
+        .delinquent
+        .iter()
+        .map(|info| &info.node_pubkey[..])
+        .filter(|node_pubkey| !current.contains(node_pubkey))
+        .collect();
+    ValidatorCounts {
+        total: (current.len() + delinquent.len()) as u64,
+        delinquent: delinquent.len() as u64,
+    }
+}
+
+/// Look up the vote account in the current and delinquent lists.
+///
+/// Returns `None` if the vote account is in neither list. This is the case
+/// when the account does not exist, or is not a vote account.
+pub fn get_vote_account_status(
+    status: &RpcVoteAccountStatus,
+    vote_account: &Pubkey,
+    current_slot: Slot,
+) -> Option<VoteAccountStatus> {
+    let vote_pubkey = vote_account.to_string();
+    let current = status.current.iter().map(|info| (info, false));
+    let delinquent = status.delinquent.iter().map(|info| (info, true));
+    current
+        .chain(delinquent)
+        .find(|(info, _)| info.vote_pubkey == vote_pubkey)
+        .map(|(info, is_delinquent)| VoteAccountStatus {
+            vote_account: *vote_account,
+            is_delinquent,
+            // The slot we observed can be slightly behind the slot that the
+            // RPC node served the vote accounts at.
+            last_vote_slot_lag: current_slot.saturating_sub(info.last_vote),
+        })
+}
+
+/// Reports the stake and validators of the cluster with `--cluster-stake`.
+pub struct ClusterStakeSource {
+    pub interval: Duration,
+}
+
+impl MetricSource for ClusterStakeSource {
+    fn name(&self) -> &'static str {
+        "cluster stake"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &[
+            "solana_cluster_active_stake_sol",
+            "solana_cluster_delinquent_stake_sol",
+            "solana_validators_delinquent",
+            "solana_validators_total",
+            "solana_vote_accounts_current",
+            "solana_vote_accounts_delinquent",
+        ]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let status = get_vote_accounts(client.rpc_client(), client.commitment())?;
+        Ok(get_cluster_stake_families(
+            &get_cluster_stake(&status),
+            &get_validator_counts(&status),
+            core.produced_at,
+        ))
+    }
+}
+
+/// Reports the delinquency and vote lag of the vote accounts of `--watch-vote-account`.
+///
+/// Operators page on these, so unlike the cluster stake, we poll them every
+/// poll by default.
+pub struct WatchedVoteAccountsSource<'a> {
+    pub vote_accounts: &'a [Pubkey],
+    pub interval: Duration,
+}
+
+impl<'a> MetricSource for WatchedVoteAccountsSource<'a> {
+    fn name(&self) -> &'static str {
+        "watched vote accounts"
+    }
+
+    fn family_names(&self) -> &'static [&'static str] {
+        &["solana_vote_delinquent", "solana_vote_last_vote_slot_lag"]
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn poll(
+        &mut self,
+        client: &SnapshotClient,
+        core: &CoreState,
+    ) -> std::result::Result<Vec<MetricFamily<'static>>, Error> {
+        let vote_accounts = get_watched_vote_accounts(
+            client.rpc_client(),
+            client.commitment(),
+            self.vote_accounts,
+            core.clock.slot,
+        )?;
+        Ok(get_vote_account_families(&vote_accounts, core.produced_at))
+    }
+}
+
+/// Return the metric families for the stake and validators of the cluster.
+fn get_cluster_stake_families(
+    cluster_stake: &ClusterStake,
+    validator_counts: &ValidatorCounts,
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    vec![
+        MetricFamily {
+            name: "solana_cluster_active_stake_sol",
+            help: "Stake delegated to vote accounts that are not delinquent, in SOL",
+            type_: "gauge",
+            metrics: vec![Metric::new(cluster_stake.active_stake).at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_cluster_delinquent_stake_sol",
+            help: "Stake delegated to delinquent vote accounts, in SOL",
+            type_: "gauge",
+            metrics: vec![Metric::new(cluster_stake.delinquent_stake).at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_vote_accounts_current",
+            help: "Number of vote accounts that are not delinquent",
+            type_: "gauge",
+            metrics: vec![Metric::new(cluster_stake.current_vote_accounts).at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_vote_accounts_delinquent",
+            help: "Number of vote accounts that are delinquent",
+            type_: "gauge",
+            metrics: vec![Metric::new(cluster_stake.delinquent_vote_accounts).at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_validators_total",
+            help: "Number of validators with a current or delinquent vote account",
+            type_: "gauge",
+            metrics: vec![Metric::new(validator_counts.total).at(produced_at)],
+        },
+        MetricFamily {
+            name: "solana_validators_delinquent",
+            help: "Number of validators whose vote accounts are all delinquent",
+            type_: "gauge",
+            metrics: vec![Metric::new(validator_counts.delinquent).at(produced_at)],
+        },
+    ]
+}
+
+/// Return the metric families for the watched vote accounts, none if there are none.
+fn get_vote_account_families(
+    vote_accounts: &[VoteAccountStatus],
+    produced_at: SystemTime,
+) -> Vec<MetricFamily<'static>> {
+    if vote_accounts.is_empty() {
+        return Vec::new();
+    }
+    vec![
+        MetricFamily {
+            name: "solana_vote_delinquent",
+            help: "1 if the vote account is delinquent, 0 otherwise",
+            type_: "gauge",
+            metrics: vote_accounts
+                .iter()
+                .map(|vote_account| {
+                    Metric::new(vote_account.is_delinquent as u64)
+                        .with_label("vote_pubkey", vote_account.vote_account.to_string())
+                        .at(produced_at)
+                })
+                .collect(),
+        },
+        MetricFamily {
+            name: "solana_vote_last_vote_slot_lag",
+            help: "Number of slots between the current slot and the last slot the vote account voted on",
+            type_: "gauge",
+            metrics: vote_accounts
+                .iter()
+                .map(|vote_account| {
+                    Metric::new(vote_account.last_vote_slot_lag)
+                        .with_label("vote_pubkey", vote_account.vote_account.to_string())
+                        .at(produced_at)
+                })
+                .collect(),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use solana_client::rpc_response::RpcVoteAccountInfo;
+
+    use super::*;
+
+    fn new_vote_account_info(activated_stake: u64) -> RpcVoteAccountInfo {
+        RpcVoteAccountInfo {
+            vote_pubkey: Pubkey::new_unique().to_string(),
+            node_pubkey: "11111111111111111111111111111111".to_string(),
+            activated_stake,
+            commission: 10,
+            epoch_vote_account: true,
+            epoch_credits: Vec::new(),
+            last_vote: 0,
+            root_slot: 0,
+        }
+    }
+
+    #[test]
+    fn get_cluster_stake_sums_per_list() {
+        let status = RpcVoteAccountStatus {
+            current: vec![new_vote_account_info(3), new_vote_account_info(5)],
+            delinquent: vec![new_vote_account_info(7)],
+        };
+        assert_eq!(
+            get_cluster_stake(&status),
+            ClusterStake {
+                active_stake: Lamports(8),
+                delinquent_stake: Lamports(7),
+                current_vote_accounts: 2,
+                delinquent_vote_accounts: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn get_cluster_stake_handles_empty_lists() {
+        let status = RpcVoteAccountStatus {
+            current: Vec::new(),
+            delinquent: Vec::new(),
+        };
+        assert_eq!(get_cluster_stake(&status).active_stake, Lamports(0));
+    }
+
+    #[test]
+    fn get_validator_counts_counts_distinct_nodes() {
+        let with_node = |node_pubkey: &str| RpcVoteAccountInfo {
+            node_pubkey: node_pubkey.to_string(),
+            ..new_vote_account_info(1)
+        };
+        let status = RpcVoteAccountStatus {
+            // Node "b" has a current and a delinquent vote account, it is not delinquent.
+            current: vec![with_node("a"), with_node("b")],
+            delinquent: vec![with_node("b"), with_node("c"), with_node("c")],
+        };
+        assert_eq!(
+            get_validator_counts(&status),
+            ValidatorCounts {
+                total: 3,
+                delinquent: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn get_vote_account_status_finds_account_in_either_list() {
+        let voting = Pubkey::new_unique();
+        let delinquent = Pubkey::new_unique();
+        let status = RpcVoteAccountStatus {
+            current: vec![
+                new_vote_account_info(1),
+                RpcVoteAccountInfo {
+                    vote_pubkey: voting.to_string(),
+                    last_vote: 995,
+                    ..new_vote_account_info(1)
+                },
+            ],
+            delinquent: vec![RpcVoteAccountInfo {
+                vote_pubkey: delinquent.to_string(),
+                last_vote: 400,
+                ..new_vote_account_info(1)
+            }],
+        };
+
+        assert_eq!(
+            get_vote_account_status(&status, &voting, 1000),
+            Some(VoteAccountStatus {
+                vote_account: voting,
+                is_delinquent: false,
+                last_vote_slot_lag: 5,
+            })
+        );
+        assert_eq!(
+            get_vote_account_status(&status, &delinquent, 1000),
+            Some(VoteAccountStatus {
+                vote_account: delinquent,
+                is_delinquent: true,
+                last_vote_slot_lag: 600,
+            })
+        );
+        assert_eq!(
+            get_vote_account_status(&status, &Pubkey::new_unique(), 1000),
+            None
+        );
+    }
+}
@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: 2022 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Exiting when a poll is stuck, so that a supervisor can restart us.
+//!
+//! The RPC timeout bounds every single request, but in rare cases a request
+//! can hang past it, e.g. on a half-open TCP connection. The daemon records
+//! when a poll starts and ends in a [`Heartbeat`], and a separate thread
+//! checks that no poll takes longer than `--watchdog-seconds`. We only
+//! consider the time spent inside a poll, so the long sleeps of the backoff
+//! after errors never trigger the watchdog.
+
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Start time of the poll that is in progress, if any.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    poll_started_at: Mutex<Option<Instant>>,
+}
+
+impl Heartbeat {
+    pub fn record_poll_start(&self, now: Instant) {
+        *self.poll_started_at.lock().unwrap() = Some(now);
+    }
+
+    pub fn record_poll_end(&self) {
+        *self.poll_started_at.lock().unwrap() = None;
+    }
+
+    /// Return how long the current poll has been running, if it is running for longer than `limit`.
+    fn get_stuck_duration(&self, now: Instant, limit: Duration) -> Option<Duration> {
+        let started_at = (*self.poll_started_at.lock().unwrap())?;
+        let duration = now.saturating_duration_since(started_at);
+        if duration > limit {
+            Some(duration)
+        } else {
+            None
+        }
+    }
+}
+
+/// Start a thread that exits the process when a poll takes longer than `limit`.
+pub fn start_watchdog(heartbeat: Arc<Heartbeat>, limit: Duration) -> JoinHandle<()> {
+    // Check a few times per limit, so we exit not much later than the limit.
+    let check_interval = (limit / 4).max(Duration::from_secs(1));
+    std::thread::Builder::new()
+        .name("watchdog".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(check_interval);
+            if let Some(duration) = heartbeat.get_stuck_duration(Instant::now(), limit) {
+                log::error!(
+                    "Poll is stuck for {:?}, longer than --watchdog-seconds, exiting.",
+                    duration,
+                );
+                std::process::exit(1);
+            }
+        })
+        .expect("Failed to spawn watchdog thread.")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn heartbeat_is_stuck_only_during_a_long_poll() {
+        let heartbeat = Heartbeat::default();
+        let limit = Duration::from_secs(60);
+        let t0 = Instant::now();
+
+        // Before the first poll, and while sleeping between polls, we are
+        // never stuck, no matter how long that takes.
+        assert_eq!(heartbeat.get_stuck_duration(t0, limit), None);
+
+        heartbeat.record_poll_start(t0);
+        assert_eq!(
+            heartbeat.get_stuck_duration(t0 + Duration::from_secs(60), limit),
+            None
+        );
+        assert_eq!(
+            heartbeat.get_stuck_duration(t0 + Duration::from_secs(61), limit),
+            Some(Duration::from_secs(61))
+        );
+
+        heartbeat.record_poll_end();
+        assert_eq!(
+            heartbeat.get_stuck_duration(t0 + Duration::from_secs(600), limit),
+            None
+        );
+
+        // A new poll starts the clock anew.
+        heartbeat.record_poll_start(t0 + Duration::from_secs(600));
+        assert_eq!(
+            heartbeat.get_stuck_duration(t0 + Duration::from_secs(630), limit),
+            None
+        );
+    }
+}